@@ -1,30 +1,88 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::{fmt::Display, vec};
 
 use anyhow::Result;
-use chrono::{FixedOffset, NaiveDateTime};
+use chrono::{DateTime, FixedOffset};
 use fuzzy_matcher::skim::SkimMatcherV2;
 use fuzzy_matcher::FuzzyMatcher;
 use git2::{
-    BranchType, Delta, DiffFormat, DiffLineType, DiffOptions, ObjectType, Oid, Repository, Time,
+    BranchType, Delta, Diff, DiffDelta, DiffFormat, DiffLineType, DiffOptions, ErrorClass,
+    ErrorCode, ObjectType, Oid, Repository, Time,
 };
 use itertools::Itertools;
+use syntect::parsing::SyntaxReference;
+
+use crate::highlight::{self, Token};
 
 pub struct GitWrapper {
     repo: Repository,
+    last_commits: RefCell<LastCommitCache>,
 }
 
-#[derive(Eq, PartialEq)]
+/// Directory listings kept in the last commit cache.
+const LAST_COMMIT_CACHE_SIZE: usize = 1024;
+
+/// The last commit of each entry in a directory, keyed by the commit the history starts from and
+/// the directory's tree id. When full, the directory used least recently is evicted.
+#[derive(Default)]
+struct LastCommitCache {
+    entries: HashMap<(Oid, Oid), (u64, HashMap<String, LastCommit>)>,
+    clock: u64,
+}
+
+impl LastCommitCache {
+    fn get(&mut self, key: (Oid, Oid)) -> Option<&HashMap<String, LastCommit>> {
+        self.clock += 1;
+        let (used, last_commits) = self.entries.get_mut(&key)?;
+        *used = self.clock;
+        Some(last_commits)
+    }
+
+    fn insert(&mut self, key: (Oid, Oid), last_commits: HashMap<String, LastCommit>) {
+        if self.entries.len() >= LAST_COMMIT_CACHE_SIZE {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (self.clock, last_commits));
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
 pub struct CommitDate(Time);
 
+impl CommitDate {
+    /// How long ago this was, e.g. "3 days ago".
+    pub fn relative(&self) -> String {
+        let seconds = (chrono::Utc::now().timestamp() - self.0.seconds()).max(0);
+        let (amount, unit) = match seconds {
+            s if s < 60 => return "just now".to_string(),
+            s if s < 60 * 60 => (s / 60, "minute"),
+            s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+            s if s < 30 * 24 * 60 * 60 => (s / (24 * 60 * 60), "day"),
+            s if s < 365 * 24 * 60 * 60 => (s / (30 * 24 * 60 * 60), "month"),
+            s => (s / (365 * 24 * 60 * 60), "year"),
+        };
+        match amount {
+            1 => format!("1 {} ago", unit),
+            _ => format!("{} {}s ago", amount, unit),
+        }
+    }
+}
+
 impl Display for CommitDate {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let offset = FixedOffset::east_opt(self.0.offset_minutes() * 60).ok_or(std::fmt::Error)?;
-        let date_time =
-            NaiveDateTime::from_timestamp_opt(self.0.seconds(), 0).ok_or(std::fmt::Error)?;
-        let date_time = date_time
-            .and_local_timezone(offset)
-            .single()
-            .ok_or(std::fmt::Error)?;
+        let date_time = DateTime::from_timestamp(self.0.seconds(), 0)
+            .ok_or(std::fmt::Error)?
+            .with_timezone(&offset);
         write!(f, "{}", date_time.to_rfc2822())
     }
 }
@@ -36,6 +94,7 @@ pub struct Commit {
     pub body: Option<String>,
     pub author: String,
     pub date: CommitDate,
+    pub parents: Vec<String>,
     sort_score: Option<i64>,
 }
 
@@ -57,296 +116,4506 @@ pub struct DiffLineData {
     pub operation: DiffLineType,
     pub old_line_number: Option<u32>,
     pub new_line_number: Option<u32>,
+    pub segments: Vec<DiffSegment>,
+}
+
+/// A piece of a changed line, marked when it differs from the line it was paired with.
+pub struct DiffSegment {
+    pub text: String,
+    pub changed: bool,
+    /// Inline CSS from syntax highlighting.
+    pub style: Option<String>,
+}
+
+fn tokenize_words(line: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let same_run = |next: char| {
+            (is_word(c) && is_word(next)) || (c.is_whitespace() && next.is_whitespace())
+        };
+        match chars.peek() {
+            Some(&(_, next)) if same_run(next) => (),
+            _ => {
+                let end = i + c.len_utf8();
+                tokens.push(&line[start..end]);
+                start = end;
+            }
+        }
+    }
+    tokens
+}
+
+/// Computes word-level changes between a deleted and an added line. Returns `None` when the lines
+/// are too long to compare or have too little in common for the highlighting to be useful.
+fn word_diff(old: &str, new: &str) -> Option<(Vec<DiffSegment>, Vec<DiffSegment>)> {
+    let (old_tokens, new_tokens) = (tokenize_words(old), tokenize_words(new));
+    let (n, m) = (old_tokens.len(), new_tokens.len());
+    if n * m > 250_000 {
+        return None;
+    }
+    // Longest common subsequence table over the tokens, filled from the end.
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = match old_tokens[i] == new_tokens[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let (mut old_changed, mut new_changed) = (vec![true; n], vec![true; m]);
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < n && j < m {
+        if old_tokens[i] == new_tokens[j] {
+            old_changed[i] = false;
+            new_changed[j] = false;
+            common += old_tokens[i].len();
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    if common * 5 < old.len().max(new.len()) {
+        return None;
+    }
+
+    let segments = |tokens: &[&str], changed: &[bool]| {
+        tokens
+            .iter()
+            .zip(changed)
+            .fold(vec![], |mut acc: Vec<DiffSegment>, (token, &changed)| {
+                match acc.last_mut() {
+                    Some(last) if last.changed == changed => last.text.push_str(token),
+                    _ => acc.push(DiffSegment {
+                        text: token.to_string(),
+                        changed,
+                        style: None,
+                    }),
+                }
+                acc
+            })
+    };
+    Some((
+        segments(&old_tokens, &old_changed),
+        segments(&new_tokens, &new_changed),
+    ))
+}
+
+/// Splits a line's segments further so that each piece also carries its syntax highlighting.
+/// The tokens come from highlighting the whole blob, so they may include trailing whitespace
+/// that the diff line has trimmed.
+fn apply_highlighting(line: &mut DiffLineData, tokens: &[Token]) {
+    let text: String = tokens.iter().map(|t| t.text.as_str()).collect();
+    if !text.starts_with(&line.content) {
+        return;
+    }
+    let segments = match line.segments.is_empty() {
+        true => vec![DiffSegment {
+            text: line.content.clone(),
+            changed: false,
+            style: None,
+        }],
+        false => std::mem::take(&mut line.segments),
+    };
+    let mut tokens = tokens.iter().map(|t| (t.text.as_str(), t.style.as_str()));
+    let mut token = tokens.next();
+    for segment in segments {
+        let mut rest = segment.text.as_str();
+        while let (false, Some((text, style))) = (rest.is_empty(), token) {
+            let len = text.len().min(rest.len());
+            line.segments.push(DiffSegment {
+                text: rest[..len].to_string(),
+                changed: segment.changed,
+                style: Some(style.to_string()),
+            });
+            rest = &rest[len..];
+            token = match text.len() > len {
+                true => Some((&text[len..], style)),
+                false => tokens.next(),
+            };
+        }
+    }
 }
 
 pub struct DiffHunkItem {
     pub hunk_diff: DiffLineData,
     pub lines: Vec<DiffLineData>,
+    pub old_start: u32,
+    pub old_lines: u32,
+    pub new_start: u32,
+    pub new_lines: u32,
+    pub context_before: Option<ContextGap>,
 }
 
-pub struct DiffFileItem {
-    pub file_diff: DiffLineData,
-    pub hunks: Vec<DiffHunkItem>,
+/// A run of unchanged lines hidden between hunks, in new-file line numbers. `end` is `None` for
+/// the lines after the last hunk, up to the end of the file.
+#[derive(Clone)]
+pub struct ContextGap {
+    pub start: u32,
+    pub end: Option<u32>,
+    pub old_offset: i64,
 }
 
-pub struct CommitFile {
-    pub id: Oid,
-    pub name: Option<String>,
-    pub kind: Option<ObjectType>,
-    pub filemode: i32,
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExpandDirection {
+    Up,
+    Down,
+    All,
 }
 
-impl GitWrapper {
-    pub fn new(repo: &str) -> Result<Self, git2::Error> {
-        let repo = Repository::open(repo)?;
-        Ok(Self { repo })
+impl std::str::FromStr for ExpandDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "up" => Ok(ExpandDirection::Up),
+            "down" => Ok(ExpandDirection::Down),
+            "all" => Ok(ExpandDirection::All),
+            _ => Err(anyhow::anyhow!("Unknown expand direction: {}", s)),
+        }
     }
+}
 
-    pub fn inner(&self) -> &Repository {
-        &self.repo
+impl ContextGap {
+    pub fn is_leading(&self) -> bool {
+        self.start == 1
     }
 
-    pub fn get_current_branch(&self) -> Result<String, git2::Error> {
-        Ok(self
-            .repo
-            .head()?
-            .shorthand()
-            .ok_or(git2::Error::from_str("Invalid utf-8 branch name"))?
-            .to_owned())
+    pub fn is_trailing(&self) -> bool {
+        self.end.is_none()
     }
 
-    pub fn list_local_branches(&self) -> Result<Vec<String>> {
-        Ok(self
-            .repo
-            .branches(Some(BranchType::Local))?
-            .into_iter()
-            .filter_map(|b| match b.ok()?.0.name() {
-                Ok(Some(name)) => Some(name.to_owned()),
-                _ => None,
+    /// Splits the gap into the lines to reveal and the gap that stays hidden. The remaining gap
+    /// sits above the revealed lines when expanding upwards and below them otherwise.
+    pub fn expand(
+        &self,
+        direction: ExpandDirection,
+        step: u32,
+        line_count: u32,
+    ) -> (std::ops::RangeInclusive<u32>, Option<ContextGap>) {
+        let end = self.end.unwrap_or(line_count).min(line_count);
+        let step = step.max(1);
+        let remaining = |start, end: u32| {
+            Some(ContextGap {
+                start,
+                end: match self.end {
+                    None if end == line_count => None,
+                    _ => Some(end),
+                },
+                old_offset: self.old_offset,
             })
-            .collect::<Vec<String>>())
+            .filter(|_| start <= end)
+        };
+        match direction {
+            ExpandDirection::All => (self.start..=end, None),
+            ExpandDirection::Up => {
+                let from = end.saturating_sub(step - 1).max(self.start);
+                (from..=end, remaining(self.start, from.saturating_sub(1)))
+            }
+            ExpandDirection::Down => {
+                let to = self.start.saturating_add(step - 1).min(end);
+                (self.start..=to, remaining(to.saturating_add(1), end))
+            }
+        }
     }
+}
 
-    pub fn list_remotes(&self) -> Result<Vec<String>, git2::Error> {
-        Ok(self
-            .repo
-            .remotes()?
-            .into_iter()
-            .flat_map(|r| r.and_then(|r| Some(r.to_string())))
-            .collect())
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    Myers,
+    Minimal,
+    Patience,
+}
+
+impl std::str::FromStr for DiffAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "myers" => Ok(DiffAlgorithm::Myers),
+            "minimal" => Ok(DiffAlgorithm::Minimal),
+            "patience" => Ok(DiffAlgorithm::Patience),
+            _ => Err(anyhow::anyhow!("Unknown diff algorithm: {}", s)),
+        }
     }
+}
 
-    pub fn list_remote_branches(&self, remote: &str) -> Result<Vec<String>, git2::Error> {
-        let mut remote = self.repo.find_remote(remote)?;
-        remote.connect(git2::Direction::Fetch)?;
-        Ok(remote
-            .list()?
-            .into_iter()
-            .map(|head| head.name())
-            .filter(|head_name| head_name.starts_with("refs/heads/"))
-            .map(|head_name| head_name.replace("refs/heads/", ""))
-            .collect())
+impl Display for DiffAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiffAlgorithm::Myers => write!(f, "myers"),
+            DiffAlgorithm::Minimal => write!(f, "minimal"),
+            DiffAlgorithm::Patience => write!(f, "patience"),
+        }
     }
+}
 
-    pub fn find_commit(&self, sha: &str) -> Result<Commit, git2::Error> {
-        let commit = self.repo.find_commit(git2::Oid::from_str(sha)?)?;
-        let summary = commit.summary().map(|v| v.to_string());
-        let body = commit.body().map(|v| v.to_string());
-        let author = commit.author().to_string();
-        Ok(Commit {
-            id: commit.id().to_string(),
-            summary,
-            body,
-            author,
-            date: CommitDate(commit.time()),
-            sort_score: None,
-        })
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WhitespaceMode {
+    Show,
+    IgnoreChange,
+    IgnoreAll,
+    IgnoreEol,
+    IgnoreBlankLines,
+}
+
+impl std::str::FromStr for WhitespaceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "show" => Ok(WhitespaceMode::Show),
+            "ignore-change" => Ok(WhitespaceMode::IgnoreChange),
+            "ignore-all" => Ok(WhitespaceMode::IgnoreAll),
+            "ignore-eol" => Ok(WhitespaceMode::IgnoreEol),
+            "ignore-blank-lines" => Ok(WhitespaceMode::IgnoreBlankLines),
+            _ => Err(anyhow::anyhow!("Unknown whitespace mode: {}", s)),
+        }
     }
+}
 
-    pub fn commit_diff(
-        &self,
-        sha: &str,
-        ignore_whitespace: bool,
-    ) -> Result<Vec<DiffFileItem>, git2::Error> {
-        let commit = self.repo.find_commit(git2::Oid::from_str(sha)?)?;
-        let commit_tree = commit.tree()?;
-        let commit_parent = commit
-            .parents()
-            .next()
-            .ok_or(git2::Error::from_str("Could not find parent commit."))?;
-        let commit_parent_tree = commit_parent.tree()?;
-        let mut diff_options = DiffOptions::new();
-        diff_options.ignore_whitespace(ignore_whitespace);
-        let diff = self.repo.diff_tree_to_tree(
-            Some(&commit_parent_tree),
-            Some(&commit_tree),
-            Some(&mut diff_options),
-        )?;
+impl Display for WhitespaceMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WhitespaceMode::Show => write!(f, "show"),
+            WhitespaceMode::IgnoreChange => write!(f, "ignore-change"),
+            WhitespaceMode::IgnoreAll => write!(f, "ignore-all"),
+            WhitespaceMode::IgnoreEol => write!(f, "ignore-eol"),
+            WhitespaceMode::IgnoreBlankLines => write!(f, "ignore-blank-lines"),
+        }
+    }
+}
 
-        let mut result = Vec::new();
-        diff.print(DiffFormat::Patch, |delta, _hunk, line| {
-            let content = match line.origin_value() {
-                DiffLineType::FileHeader => {
-                    let old_file = delta.old_file();
-                    let new_file = delta.new_file();
-                    match delta.status() {
-                        Delta::Added => format!("[Added] {}", new_file.path().unwrap().display()),
-                        Delta::Copied => format!(
-                            "[Copied] {} -> {}",
-                            old_file.path().unwrap().display(),
-                            new_file.path().unwrap().display()
-                        ),
-                        Delta::Deleted => {
-                            format!("[Deleted] {}", old_file.path().unwrap().display())
-                        }
-                        Delta::Renamed => format!(
-                            "[Renamed] {} -> {}",
-                            old_file.path().unwrap().display(),
-                            new_file.path().unwrap().display()
-                        ),
-                        Delta::Modified => {
-                            format!("[Modified] {}", new_file.path().unwrap().display())
-                        }
-                        Delta::Ignored => {
-                            format!("[Ignored] {}", new_file.path().unwrap().display())
-                        }
-                        Delta::Conflicted => {
-                            format!("[Conflicted] {}", new_file.path().unwrap().display())
-                        }
-                        _ => new_file.path().unwrap().display().to_string(),
-                    }
-                }
-                _ => std::str::from_utf8(line.content())
-                    .unwrap_or("FAILED TO PARSE")
-                    .trim_end()
-                    .to_string(),
-            };
-            let file_path = match line.origin_value() {
-                DiffLineType::FileHeader => delta
-                    .new_file()
-                    .path()
-                    .map(|p| p.to_str().unwrap_or("").to_owned()),
-                _ => None,
-            };
+pub struct DiffSettings {
+    pub whitespace: WhitespaceMode,
+    pub algorithm: DiffAlgorithm,
+    pub context_lines: u32,
+    pub interhunk_lines: u32,
+    pub find_renames: bool,
+    pub find_copies: bool,
+    pub similarity_threshold: u16,
+    pub max_files: usize,
+    pub max_file_lines: usize,
+    pub max_bytes: usize,
+}
 
-            result.push(DiffLineData {
-                content,
-                file_path,
-                operation: line.origin_value(),
-                old_line_number: line.old_lineno(),
-                new_line_number: line.new_lineno(),
-            });
-            true
-        })?;
-        let result = result.into_iter().fold(vec![], |mut acc, l| {
-            match l.operation {
-                DiffLineType::FileHeader => acc.push(DiffFileItem {
-                    file_diff: l,
-                    hunks: vec![],
-                }),
-                DiffLineType::HunkHeader => acc.last_mut().unwrap().hunks.push(DiffHunkItem {
-                    hunk_diff: l,
-                    lines: vec![],
-                }),
-                DiffLineType::Binary => (),
-                _ => acc
-                    .last_mut()
-                    .unwrap()
-                    .hunks
-                    .last_mut()
-                    .unwrap()
-                    .lines
-                    .push(l),
-            }
-            acc
-        });
-        Ok(result)
+impl Default for DiffSettings {
+    fn default() -> Self {
+        Self {
+            whitespace: WhitespaceMode::Show,
+            algorithm: DiffAlgorithm::Myers,
+            context_lines: 3,
+            interhunk_lines: 0,
+            find_renames: true,
+            find_copies: false,
+            similarity_threshold: 50,
+            max_files: 300,
+            max_file_lines: 2000,
+            max_bytes: 1024 * 1024,
+        }
     }
+}
 
-    pub fn commit_file_content(&self, sha: &str, path: &str) -> Result<String, git2::Error> {
-        let commit = self.repo.find_commit(git2::Oid::from_str(sha)?)?;
-        let tree = commit.tree()?;
-        let entry = tree.get_path(&std::path::Path::new(path))?;
-        let obj = entry.to_object(&self.repo)?;
-        let blob = obj
-            .as_blob()
-            .ok_or(git2::Error::from_str("Unable to get blob from object"))?;
-        let content = std::str::from_utf8(blob.content())
-            .or(Err(git2::Error::from_str("Unable to get blob content")))?;
-        Ok(content.to_string())
-    }
-
-    pub fn commit_file_tree(&self, sha: &str) -> Result<git2::Tree, git2::Error> {
-        let commit = self.repo.find_commit(git2::Oid::from_str(sha)?)?;
-        commit.tree()
+impl DiffSettings {
+    fn diff_options(&self) -> DiffOptions {
+        let mut options = DiffOptions::new();
+        options
+            .context_lines(self.context_lines)
+            .interhunk_lines(self.interhunk_lines)
+            .ignore_whitespace(self.whitespace == WhitespaceMode::IgnoreAll)
+            .ignore_whitespace_change(self.whitespace == WhitespaceMode::IgnoreChange)
+            .ignore_whitespace_eol(self.whitespace == WhitespaceMode::IgnoreEol)
+            .ignore_blank_lines(self.whitespace == WhitespaceMode::IgnoreBlankLines)
+            .minimal(self.algorithm == DiffAlgorithm::Minimal)
+            .patience(self.algorithm == DiffAlgorithm::Patience);
+        options
     }
 
-    pub fn get_file_list_for_commit(
-        &self,
-        sha: &str,
-        path: Option<&str>,
-    ) -> Result<Vec<CommitFile>, git2::Error> {
-        let commit = self.repo.find_commit(git2::Oid::from_str(sha)?)?;
-        let tree = commit.tree()?;
-        let obj = match path {
-            Some(path) => tree
-                .get_path(&std::path::Path::new(path))?
-                .to_object(&self.repo)?,
-            None => tree.into_object(),
-        };
-        let mut file_list = obj
-            .into_tree()
-            .map_err(|_| git2::Error::from_str("Could not get tree"))?
-            .iter()
-            .map(|t| CommitFile {
-                id: t.id(),
-                name: t.name().map(|t| t.to_string()),
-                kind: t.kind(),
-                filemode: t.filemode(),
-            })
-            .collect_vec();
-        file_list.sort_by_key(|f| match f.kind {
-            Some(ObjectType::Tree) => 0,
-            _ => 1,
-        });
-        Ok(file_list)
+    fn find_options(&self) -> Option<git2::DiffFindOptions> {
+        if !self.find_renames && !self.find_copies {
+            return None;
+        }
+        let mut options = git2::DiffFindOptions::new();
+        options
+            .renames(self.find_renames)
+            .copies(self.find_copies)
+            .copies_from_unmodified(self.find_copies)
+            .rename_threshold(self.similarity_threshold)
+            .copy_threshold(self.similarity_threshold);
+        Some(options)
     }
+}
 
-    pub fn checkout_local_branch(&self, branch: &str) -> Result<(), git2::Error> {
-        let branch_ref = &format!("refs/heads/{}", branch);
-        let obj = self.repo.revparse_single(branch_ref)?;
-        let _ = self.repo.checkout_tree(&obj, None)?;
-        let _ = self.repo.set_head(branch_ref)?;
-        Ok(())
+pub struct SplitDiffRow<'a> {
+    pub left: Option<&'a DiffLineData>,
+    pub right: Option<&'a DiffLineData>,
+}
+
+impl DiffHunkItem {
+    /// Pairs each run of deleted lines with the added lines that follow it, as indices into
+    /// `lines`. Context lines are paired with themselves.
+    fn paired_lines(&self) -> Vec<(Option<usize>, Option<usize>)> {
+        fn flush(
+            rows: &mut Vec<(Option<usize>, Option<usize>)>,
+            deletions: &mut Vec<usize>,
+            additions: &mut Vec<usize>,
+        ) {
+            rows.extend(
+                deletions
+                    .drain(..)
+                    .zip_longest(additions.drain(..))
+                    .map(|pair| (pair.clone().left(), pair.right())),
+            );
+        }
+
+        let mut rows = vec![];
+        let (mut deletions, mut additions) = (vec![], vec![]);
+        for (i, line) in self.lines.iter().enumerate() {
+            match line.operation {
+                DiffLineType::Deletion | DiffLineType::DeleteEOFNL => {
+                    if !additions.is_empty() {
+                        flush(&mut rows, &mut deletions, &mut additions);
+                    }
+                    deletions.push(i);
+                }
+                DiffLineType::Addition | DiffLineType::AddEOFNL => additions.push(i),
+                _ => {
+                    flush(&mut rows, &mut deletions, &mut additions);
+                    rows.push((Some(i), Some(i)));
+                }
+            }
+        }
+        flush(&mut rows, &mut deletions, &mut additions);
+        rows
     }
 
-    pub fn list_commits<'a>(
-        &'a self,
-        reference: &str,
-        filter: Option<&'a str>,
-    ) -> Result<impl Iterator<Item = Commit> + 'a> {
-        let obj = self.repo.revparse_single(reference)?;
-        let mut revwalk = self.repo.revwalk()?;
-        revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
-        revwalk.push(obj.id())?;
+    /// Pairs deletions with the additions that replace them, so the old and new sides of the
+    /// hunk can be rendered next to each other.
+    pub fn split_rows(&self) -> Vec<SplitDiffRow<'_>> {
+        self.paired_lines()
+            .into_iter()
+            .map(|(left, right)| SplitDiffRow {
+                left: left.map(|i| &self.lines[i]),
+                right: right.map(|i| &self.lines[i]),
+            })
+            .collect()
+    }
 
-        let matcher = SkimMatcherV2::default();
-        let result = revwalk.filter_map(move |id| match id {
-            Ok(id) => match (filter, self.repo.find_commit(id)) {
-                (Some(filter), Ok(commit)) => {
-                    let message = commit.message().map(|v| v.to_string());
-                    let summary = commit.summary().map(|v| v.to_string());
-                    let body = commit.body().map(|v| v.to_string());
-                    let score = match message.clone() {
-                        Some(msg) => matcher.fuzzy_match(&msg, &filter),
-                        None => None,
-                    };
-                    return score.and_then(move |score| {
-                        Some(Commit {
-                            id: id.to_string(),
-                            summary,
-                            body,
-                            author: commit.author().to_string(),
-                            date: CommitDate(commit.time()),
-                            sort_score: Some(score),
-                        })
-                    });
+    /// Fills in the word-level segments of every deleted line that has a matching added line.
+    fn highlight_changes(&mut self) {
+        for (left, right) in self.paired_lines() {
+            if let (Some(left), Some(right)) = (left, right) {
+                if left == right {
+                    continue;
                 }
-                (None, Ok(commit)) => Some(Commit {
-                    id: id.to_string(),
-                    summary: commit.summary().map(|v| v.to_string()),
-                    body: commit.body().map(|v| v.to_string()),
-                    author: commit.author().to_string(),
-                    date: CommitDate(commit.time()),
-                    sort_score: None,
-                }),
-                _ => None,
-            },
-            _ => None,
-        });
-        match filter {
-            Some(_) => Ok(itertools::Either::Right(result.sorted())),
-            None => Ok(itertools::Either::Left(result)),
+                if let Some((old, new)) =
+                    word_diff(&self.lines[left].content, &self.lines[right].content)
+                {
+                    self.lines[left].segments = old;
+                    self.lines[right].segments = new;
+                }
+            }
         }
     }
 }
+
+pub struct DiffFileItem {
+    pub file_diff: DiffLineData,
+    pub hunks: Vec<DiffHunkItem>,
+    pub context_after: Option<ContextGap>,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+    pub generated: bool,
+    /// Set when the hunks were left out to keep the page small; they can be loaded separately.
+    pub truncated: Option<Truncation>,
+    pub old_blob: Option<DiffBlob>,
+    pub new_blob: Option<DiffBlob>,
+    pub mime_type: String,
+}
+
+pub struct RawFile {
+    pub content: Vec<u8>,
+    pub mime_type: String,
+}
+
+pub struct FileBlob {
+    pub id: String,
+    pub size: u64,
+    pub content: BlobContent,
+}
+
+impl FileBlob {
+    pub fn size_display(&self) -> String {
+        format_size(self.size)
+    }
+}
+
+pub enum BlobContent {
+    Text {
+        encoding: &'static str,
+        lines: Vec<Vec<Token>>,
+    },
+    Binary {
+        rows: Vec<HexRow>,
+        truncated: bool,
+    },
+}
+
+/// Binary files show at most this many bytes as a hex dump.
+const HEX_DUMP_BYTES: usize = 64 * 1024;
+
+/// Extensions a directory README may have, most preferred first.
+const README_EXTENSIONS: [&str; 6] = ["md", "markdown", "", "txt", "rst", "org"];
+
+pub struct HexRow {
+    pub offset: String,
+    pub hex: String,
+    pub ascii: String,
+}
+
+fn hex_dump(bytes: &[u8]) -> Vec<HexRow> {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| HexRow {
+            offset: format!("{:08x}", i * 16),
+            hex: chunk
+                .chunks(8)
+                .map(|half| half.iter().map(|b| format!("{:02x}", b)).join(" "))
+                .join("  "),
+            ascii: chunk
+                .iter()
+                .map(|b| match b.is_ascii_graphic() || *b == b' ' {
+                    true => *b as char,
+                    false => '.',
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+pub struct DiffBlob {
+    pub id: String,
+    pub path: String,
+    pub size: u64,
+}
+
+impl DiffBlob {
+    pub fn size_display(&self) -> String {
+        format_size(self.size)
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    for unit in ["KiB", "MiB"] {
+        if size < 1024.0 {
+            return format!("{:.1} {}", size, unit);
+        }
+        size /= 1024.0;
+    }
+    format!("{:.1} GiB", size)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Truncation {
+    TooManyFiles,
+    TooManyLines,
+    TooManyBytes,
+}
+
+impl Truncation {
+    /// Files left out only to keep the page small are loaded once scrolled into view, while
+    /// files that are huge themselves wait to be asked for.
+    pub fn loads_when_visible(&self) -> bool {
+        *self != Truncation::TooManyLines
+    }
+}
+
+impl Display for Truncation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Truncation::TooManyFiles => {
+                write!(f, "This commit changes too many files to show them all.")
+            }
+            Truncation::TooManyLines => write!(f, "This diff is too large to show by default."),
+            Truncation::TooManyBytes => {
+                write!(f, "This commit's diff is too large to show completely.")
+            }
+        }
+    }
+}
+
+/// Files with more changed lines than this start out collapsed.
+const LARGE_DIFF_LINES: usize = 400;
+
+/// Lock files and build output that are rarely worth reading in a diff.
+const GENERATED_PATTERNS: [&str; 9] = [
+    "Cargo.lock",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "go.sum",
+    "*.min.js",
+    "*.min.css",
+    "*.map",
+    "*.snap",
+];
+
+/// The element id of a file in a diff. Other bytes than ASCII letters and digits are written as
+/// `-` and two hex digits, so that different paths never share an id.
+fn diff_anchor(path: &str) -> String {
+    let mut anchor = "diff-".to_string();
+    for byte in path.bytes() {
+        match byte.is_ascii_alphanumeric() {
+            true => anchor.push(byte as char),
+            false => anchor.push_str(&format!("-{:02x}", byte)),
+        }
+    }
+    anchor
+}
+
+impl DiffFileItem {
+    pub fn path(&self) -> &str {
+        self.file_diff.file_path.as_deref().unwrap_or("")
+    }
+
+    pub fn anchor(&self) -> String {
+        diff_anchor(self.path())
+    }
+
+    pub fn is_image(&self) -> bool {
+        matches!(
+            self.mime_type.as_str(),
+            "image/png" | "image/jpeg" | "image/gif" | "image/svg+xml" | "image/webp"
+        )
+    }
+
+    pub fn collapsed(&self) -> bool {
+        self.generated || self.insertions + self.deletions > LARGE_DIFF_LINES
+    }
+}
+
+pub struct DiffStat {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub rows: Vec<DiffStatRow>,
+}
+
+/// A line of the file tree summary: either a directory or a changed file inside it.
+pub struct DiffStatRow {
+    pub name: String,
+    pub depth: usize,
+    pub anchor: Option<String>,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+    /// Widths of the insertion and deletion bars, as percentages of the largest change.
+    pub insertion_width: usize,
+    pub deletion_width: usize,
+}
+
+impl DiffStat {
+    pub fn new(files: &[DiffFileItem]) -> DiffStat {
+        let largest = files
+            .iter()
+            .map(|f| f.insertions + f.deletions)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let mut rows = vec![];
+        let mut open_dirs: Vec<&str> = vec![];
+        for file in files.iter().sorted_by(|a, b| a.path().cmp(b.path())) {
+            let mut components = file.path().split('/').collect::<Vec<_>>();
+            let name = components.pop().unwrap_or_default();
+            let shared = open_dirs
+                .iter()
+                .zip(components.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            open_dirs.truncate(shared);
+            for dir in &components[shared..] {
+                rows.push(DiffStatRow {
+                    name: format!("{}/", dir),
+                    depth: open_dirs.len(),
+                    anchor: None,
+                    insertions: 0,
+                    deletions: 0,
+                    binary: false,
+                    insertion_width: 0,
+                    deletion_width: 0,
+                });
+                open_dirs.push(dir);
+            }
+            rows.push(DiffStatRow {
+                name: name.to_string(),
+                depth: open_dirs.len(),
+                anchor: Some(file.anchor()),
+                insertions: file.insertions,
+                deletions: file.deletions,
+                binary: file.binary,
+                insertion_width: (file.insertions * 100).div_ceil(largest),
+                deletion_width: (file.deletions * 100).div_ceil(largest),
+            });
+        }
+        DiffStat {
+            files_changed: files.len(),
+            insertions: files.iter().map(|f| f.insertions).sum(),
+            deletions: files.iter().map(|f| f.deletions).sum(),
+            rows,
+        }
+    }
+}
+
+fn file_header(delta: &DiffDelta) -> String {
+    let old_file = delta.old_file();
+    let new_file = delta.new_file();
+    match delta.status() {
+        Delta::Added => format!("[Added] {}", new_file.path().unwrap().display()),
+        Delta::Copied => format!(
+            "[Copied] {} -> {}",
+            old_file.path().unwrap().display(),
+            new_file.path().unwrap().display()
+        ),
+        Delta::Deleted => {
+            format!("[Deleted] {}", old_file.path().unwrap().display())
+        }
+        Delta::Renamed => format!(
+            "[Renamed] {} -> {}",
+            old_file.path().unwrap().display(),
+            new_file.path().unwrap().display()
+        ),
+        Delta::Modified => {
+            format!("[Modified] {}", new_file.path().unwrap().display())
+        }
+        Delta::Ignored => {
+            format!("[Ignored] {}", new_file.path().unwrap().display())
+        }
+        Delta::Conflicted => {
+            format!("[Conflicted] {}", new_file.path().unwrap().display())
+        }
+        _ => new_file.path().unwrap().display().to_string(),
+    }
+}
+
+pub struct CommitFile {
+    pub id: Oid,
+    pub name: Option<String>,
+    pub kind: Option<ObjectType>,
+    pub filemode: i32,
+    pub last_commit: Option<LastCommit>,
+    pub size: Option<u64>,
+    pub symlink: Option<Symlink>,
+    pub submodule: Option<Submodule>,
+}
+
+impl CommitFile {
+    pub fn is_executable(&self) -> bool {
+        self.filemode == i32::from(git2::FileMode::BlobExecutable)
+    }
+
+    pub fn size_display(&self) -> Option<String> {
+        self.size.map(format_size)
+    }
+}
+
+pub struct Symlink {
+    pub target: String,
+    /// The path the target resolves to, if it stays inside the repository and exists.
+    pub path: Option<String>,
+}
+
+/// A submodule entry, pinned to a commit of another repository.
+pub struct Submodule {
+    pub commit: String,
+    pub url: Option<String>,
+}
+
+impl Submodule {
+    /// The URL if a browser can open it.
+    pub fn web_url(&self) -> Option<&str> {
+        self.url
+            .as_deref()
+            .filter(|url| url.starts_with("https://") || url.starts_with("http://"))
+    }
+}
+
+/// Reads the submodule URLs from a `.gitmodules` file, keyed by submodule path.
+fn parse_gitmodules(content: &str) -> HashMap<String, String> {
+    let mut urls = HashMap::new();
+    let (mut path, mut url) = (None, None);
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            if let (Some(path), Some(url)) = (path.take(), url.take()) {
+                urls.insert(path, url);
+            }
+            (path, url) = (None, None);
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').to_string();
+        match key.trim() {
+            "path" => path = Some(value),
+            "url" => url = Some(value),
+            _ => (),
+        }
+    }
+    if let (Some(path), Some(url)) = (path, url) {
+        urls.insert(path, url);
+    }
+    urls
+}
+
+/// Resolves a symlink target relative to the directory holding the link. Returns `None` when
+/// the target is absolute or leaves the repository.
+fn resolve_symlink(directory: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') {
+        return None;
+    }
+    let mut segments: Vec<&str> = directory.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in target.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+/// The most recent commit that touched a tree entry.
+#[derive(Clone)]
+pub struct LastCommit {
+    pub id: String,
+    pub summary: Option<String>,
+    pub author: String,
+    pub date: CommitDate,
+}
+
+impl From<&git2::Commit<'_>> for LastCommit {
+    fn from(commit: &git2::Commit<'_>) -> LastCommit {
+        LastCommit {
+            id: commit.id().to_string(),
+            summary: commit.summary().map(|summary| summary.to_string()),
+            author: commit.author().name().unwrap_or("").to_string(),
+            date: CommitDate(commit.time()),
+        }
+    }
+}
+
+/// The id of the directory at `path` in a commit, or `None` if it does not exist there.
+fn directory_id(commit: &git2::Commit<'_>, path: Option<&str>) -> Option<Oid> {
+    match path {
+        None => Some(commit.tree_id()),
+        Some(path) => commit
+            .tree()
+            .ok()?
+            .get_path(std::path::Path::new(path))
+            .ok()
+            .filter(|entry| entry.kind() == Some(ObjectType::Tree))
+            .map(|entry| entry.id()),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MergeMode {
+    FastForward,
+    FastForwardOnly,
+    NoFastForward,
+    Squash,
+}
+
+impl std::str::FromStr for MergeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ff" => Ok(MergeMode::FastForward),
+            "ff-only" => Ok(MergeMode::FastForwardOnly),
+            "no-ff" => Ok(MergeMode::NoFastForward),
+            "squash" => Ok(MergeMode::Squash),
+            _ => Err(anyhow::anyhow!("Unknown merge mode: {}", s)),
+        }
+    }
+}
+
+pub struct MergePreview {
+    pub source_id: String,
+    pub unrelated: bool,
+    pub up_to_date: bool,
+    pub fast_forward: bool,
+    pub conflicts: Vec<String>,
+    pub commits: Vec<Commit>,
+}
+
+pub enum MergeOutcome {
+    UpToDate,
+    FastForwarded(String),
+    Committed(String),
+    Conflicted(Vec<String>),
+}
+
+pub struct MergeStatus {
+    pub state: String,
+    pub clean: bool,
+    pub operation_head: Option<String>,
+    pub message: Option<String>,
+    pub conflicts: Vec<String>,
+}
+
+impl MergeStatus {
+    pub fn in_progress(&self) -> bool {
+        !self.clean || !self.conflicts.is_empty()
+    }
+}
+
+/// Error for merging commits that share no history.
+fn unrelated_histories(ours: &str, theirs: &str) -> git2::Error {
+    git2::Error::new(
+        git2::ErrorCode::Invalid,
+        git2::ErrorClass::Merge,
+        format!(
+            "{} and {} have no common ancestor and cannot be merged",
+            ours, theirs
+        ),
+    )
+}
+
+fn conflicted_paths(index: &git2::Index) -> Result<Vec<String>, git2::Error> {
+    let mut paths = index
+        .conflicts()?
+        .filter_map(|conflict| {
+            let conflict = conflict.ok()?;
+            let entry = conflict.our.or(conflict.their).or(conflict.ancestor)?;
+            Some(String::from_utf8_lossy(&entry.path).to_string())
+        })
+        .collect_vec();
+    paths.dedup();
+    Ok(paths)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ConflictSide {
+    Ours,
+    Theirs,
+    Ancestor,
+    Both,
+}
+
+impl std::str::FromStr for ConflictSide {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ours" => Ok(ConflictSide::Ours),
+            "theirs" => Ok(ConflictSide::Theirs),
+            "ancestor" => Ok(ConflictSide::Ancestor),
+            "both" => Ok(ConflictSide::Both),
+            _ => Err(anyhow::anyhow!("Unknown conflict side: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConflictSegment {
+    Resolved(String),
+    Conflict {
+        index: usize,
+        ours: String,
+        ancestor: String,
+        theirs: String,
+    },
+}
+
+pub struct ConflictFile {
+    pub path: String,
+    pub binary: bool,
+    pub ancestor: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+    pub merged: String,
+    pub segments: Vec<ConflictSegment>,
+}
+
+impl ConflictFile {
+    pub fn conflict_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s, ConflictSegment::Conflict { .. }))
+            .count()
+    }
+
+    /// Builds the resolved file content by picking a side for every conflicting hunk, in order.
+    pub fn resolve(&self, sides: &[ConflictSide]) -> Result<String> {
+        if sides.len() != self.conflict_count() {
+            return Err(anyhow::anyhow!(
+                "Expected a choice for {} conflicting hunks, got {}",
+                self.conflict_count(),
+                sides.len()
+            ));
+        }
+        let mut sides = sides.iter();
+        Ok(self
+            .segments
+            .iter()
+            .map(|segment| match segment {
+                ConflictSegment::Resolved(text) => text.to_owned(),
+                ConflictSegment::Conflict {
+                    ours,
+                    ancestor,
+                    theirs,
+                    ..
+                } => match sides.next() {
+                    Some(ConflictSide::Ours) => ours.to_owned(),
+                    Some(ConflictSide::Theirs) => theirs.to_owned(),
+                    Some(ConflictSide::Ancestor) => ancestor.to_owned(),
+                    _ => format!("{}{}", ours, theirs),
+                },
+            })
+            .collect())
+    }
+}
+
+/// Conflict markers are made longer than any run of marker characters starting a line in the
+/// merged files, so that such lines are never mistaken for markers.
+fn conflict_marker_size(texts: &[&str]) -> usize {
+    texts
+        .iter()
+        .flat_map(|text| text.lines())
+        .filter_map(|line| {
+            let marker = line.chars().next().filter(|c| "<|=>".contains(*c))?;
+            Some(line.chars().take_while(|c| *c == marker).count() + 1)
+        })
+        .fold(7, usize::max)
+}
+
+/// Merges the three sides of a conflicted text file, returning the merged text with diff3-style
+/// conflict markers and its segments.
+fn merge_conflicted(ancestor: &str, ours: &str, theirs: &str) -> (String, Vec<ConflictSegment>) {
+    let marker_size = conflict_marker_size(&[ancestor, ours, theirs]);
+    let mut options = diffy::MergeOptions::new();
+    options
+        .set_conflict_marker_length(marker_size)
+        .set_conflict_style(diffy::ConflictStyle::Diff3)
+        .set_incomplete_hunk_style(diffy::IncompleteHunkStyle::Git);
+    let merged = options
+        .merge(ancestor, ours, theirs)
+        .unwrap_or_else(|conflicted| conflicted);
+    let segments = parse_conflict_markers(&merged, marker_size);
+    (merged, segments)
+}
+
+/// Splits the output of a diff3-style merge back into clean text and conflicting hunks.
+fn parse_conflict_markers(merged: &str, marker_size: usize) -> Vec<ConflictSegment> {
+    enum Section {
+        Clean,
+        Ours,
+        Ancestor,
+        Theirs,
+    }
+    let [start, base, separator, end] = [
+        format!("{} ours", "<".repeat(marker_size)),
+        format!("{} original", "|".repeat(marker_size)),
+        "=".repeat(marker_size),
+        format!("{} theirs", ">".repeat(marker_size)),
+    ];
+    let mut segments = vec![];
+    let mut section = Section::Clean;
+    let (mut clean, mut ours, mut ancestor, mut theirs) =
+        (String::new(), String::new(), String::new(), String::new());
+    for line in merged.split_inclusive('\n') {
+        let marker = line.trim_end_matches('\n');
+        match section {
+            Section::Clean if marker == start => {
+                if !clean.is_empty() {
+                    segments.push(ConflictSegment::Resolved(std::mem::take(&mut clean)));
+                }
+                section = Section::Ours;
+            }
+            Section::Ours if marker == base => section = Section::Ancestor,
+            Section::Ours | Section::Ancestor if marker == separator => section = Section::Theirs,
+            Section::Theirs if marker == end => {
+                segments.push(ConflictSegment::Conflict {
+                    index: segments
+                        .iter()
+                        .filter(|s| matches!(s, ConflictSegment::Conflict { .. }))
+                        .count(),
+                    ours: std::mem::take(&mut ours),
+                    ancestor: std::mem::take(&mut ancestor),
+                    theirs: std::mem::take(&mut theirs),
+                });
+                section = Section::Clean;
+            }
+            Section::Clean => clean.push_str(line),
+            Section::Ours => ours.push_str(line),
+            Section::Ancestor => ancestor.push_str(line),
+            Section::Theirs => theirs.push_str(line),
+        }
+    }
+    if !clean.is_empty() {
+        segments.push(ConflictSegment::Resolved(clean));
+    }
+    segments
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    Soft,
+    Mixed,
+    Hard,
+}
+
+impl std::str::FromStr for ResetMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "soft" => Ok(ResetMode::Soft),
+            "mixed" => Ok(ResetMode::Mixed),
+            "hard" => Ok(ResetMode::Hard),
+            _ => Err(anyhow::anyhow!("Unknown reset mode: {}", s)),
+        }
+    }
+}
+
+impl Display for ResetMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetMode::Soft => write!(f, "soft"),
+            ResetMode::Mixed => write!(f, "mixed"),
+            ResetMode::Hard => write!(f, "hard"),
+        }
+    }
+}
+
+impl From<ResetMode> for git2::ResetType {
+    fn from(mode: ResetMode) -> Self {
+        match mode {
+            ResetMode::Soft => git2::ResetType::Soft,
+            ResetMode::Mixed => git2::ResetType::Mixed,
+            ResetMode::Hard => git2::ResetType::Hard,
+        }
+    }
+}
+
+pub struct ReflogEntry {
+    pub old_id: Option<String>,
+    pub new_id: String,
+    pub message: Option<String>,
+    pub committer: String,
+    pub date: CommitDate,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RebaseAction {
+    Pick,
+    Reword,
+    Squash,
+    Fixup,
+    Drop,
+}
+
+impl std::str::FromStr for RebaseAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pick" => Ok(RebaseAction::Pick),
+            "reword" => Ok(RebaseAction::Reword),
+            "squash" => Ok(RebaseAction::Squash),
+            "fixup" => Ok(RebaseAction::Fixup),
+            "drop" => Ok(RebaseAction::Drop),
+            _ => Err(anyhow::anyhow!("Unknown rebase action: {}", s)),
+        }
+    }
+}
+
+impl Display for RebaseAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RebaseAction::Pick => write!(f, "pick"),
+            RebaseAction::Reword => write!(f, "reword"),
+            RebaseAction::Squash => write!(f, "squash"),
+            RebaseAction::Fixup => write!(f, "fixup"),
+            RebaseAction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+pub struct RebaseStep {
+    pub action: RebaseAction,
+    pub id: String,
+    pub message: Option<String>,
+}
+
+pub struct RebaseStatus {
+    pub interactive: bool,
+    pub head_name: String,
+    pub onto: String,
+    pub current: Option<String>,
+    pub remaining: Vec<String>,
+    pub conflicts: Vec<String>,
+}
+
+impl RebaseAction {
+    /// The git rebase operation carrying out this action. Dropped commits have none.
+    fn operation_type(self) -> Option<git2::RebaseOperationType> {
+        match self {
+            RebaseAction::Pick => Some(git2::RebaseOperationType::Pick),
+            RebaseAction::Reword => Some(git2::RebaseOperationType::Reword),
+            RebaseAction::Squash => Some(git2::RebaseOperationType::Squash),
+            RebaseAction::Fixup => Some(git2::RebaseOperationType::Fixup),
+            RebaseAction::Drop => None,
+        }
+    }
+}
+
+/// The state directory git keeps for a merge-based rebase, removed when it finishes or is aborted.
+const REBASE_DIR: &str = "rebase-merge";
+
+/// The actions of an interactive rebase, one line per operation of the underlying git rebase.
+/// Reworded messages are kept next to it, in one file per commit.
+const REBASE_PLAN: &str = "htmx-git-plan";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ApplyTarget {
+    WorkDir,
+    Index,
+    Both,
+    /// Apply to the working tree and index and commit each patch, like `git am`.
+    Commit,
+}
+
+impl std::str::FromStr for ApplyTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "workdir" => Ok(ApplyTarget::WorkDir),
+            "index" => Ok(ApplyTarget::Index),
+            "both" => Ok(ApplyTarget::Both),
+            "commit" => Ok(ApplyTarget::Commit),
+            _ => Err(anyhow::anyhow!("Unknown apply target: {}", s)),
+        }
+    }
+}
+
+impl Display for ApplyTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyTarget::WorkDir => write!(f, "workdir"),
+            ApplyTarget::Index => write!(f, "index"),
+            ApplyTarget::Both => write!(f, "both"),
+            ApplyTarget::Commit => write!(f, "commit"),
+        }
+    }
+}
+
+impl From<ApplyTarget> for git2::ApplyLocation {
+    fn from(target: ApplyTarget) -> Self {
+        match target {
+            ApplyTarget::WorkDir => git2::ApplyLocation::WorkDir,
+            ApplyTarget::Index => git2::ApplyLocation::Index,
+            ApplyTarget::Both | ApplyTarget::Commit => git2::ApplyLocation::Both,
+        }
+    }
+}
+
+/// The number of lines at the start of `lines` that belong to the diff. The `-- ` line that
+/// starts the signature of `git format-patch` only ends it between hunks, as a removed line
+/// reading `- ` looks the same.
+fn diff_length(lines: &[&[u8]]) -> usize {
+    let hunk_counts = |line: &[u8]| -> Option<(u32, u32)> {
+        let line = std::str::from_utf8(line).ok()?.strip_prefix("@@ -")?;
+        let (old, rest) = line.split_once(" +")?;
+        let new = rest.split(' ').next()?;
+        let count = |range: &str| {
+            range
+                .split_once(',')
+                .map_or(Some(1), |(_, n)| n.parse().ok())
+        };
+        Some((count(old)?, count(new)?))
+    };
+    let (mut old, mut new) = (0u32, 0u32);
+    for (i, line) in lines.iter().enumerate() {
+        if old > 0 || new > 0 {
+            match line.first() {
+                Some(b'-') => old = old.saturating_sub(1),
+                Some(b'+') => new = new.saturating_sub(1),
+                Some(b'\\') => (),
+                // Context lines, including ones whose leading space was stripped in transit.
+                _ => (old, new) = (old.saturating_sub(1), new.saturating_sub(1)),
+            }
+            continue;
+        }
+        match line.trim_ascii_end() {
+            b"--" => return i,
+            line => {
+                if let Some(counts) = hunk_counts(line) {
+                    (old, new) = counts;
+                }
+            }
+        }
+    }
+    lines.len()
+}
+
+/// Decodes the RFC 2047 encoded words in a header, such as `=?UTF-8?Q?Andr=C3=A9?=`. Whitespace
+/// between two encoded words is dropped.
+fn decode_header(value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    let mut after_encoded_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+        match decode_encoded_word(candidate) {
+            Some((decoded, length)) => {
+                if !(after_encoded_word && before.trim().is_empty()) {
+                    result.push_str(before);
+                }
+                result.push_str(&decoded);
+                rest = &candidate[length..];
+                after_encoded_word = true;
+            }
+            None => {
+                result.push_str(before);
+                result.push_str("=?");
+                rest = &candidate[2..];
+                after_encoded_word = false;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Decodes the encoded word at the start of `word`, returning the text and the length of the
+/// encoded word.
+fn decode_encoded_word(word: &str) -> Option<(String, usize)> {
+    let mut parts = word.strip_prefix("=?")?.splitn(3, '?');
+    let (charset, encoding, rest) = (parts.next()?, parts.next()?, parts.next()?);
+    let text = &rest[..rest.find("?=")?];
+    if [charset, encoding, text]
+        .iter()
+        .any(|part| part.contains(char::is_whitespace))
+    {
+        return None;
+    }
+    let bytes = match encoding {
+        "B" | "b" => {
+            use base64::Engine;
+            base64::engine::general_purpose::STANDARD
+                .decode(text)
+                .ok()?
+        }
+        "Q" | "q" => {
+            let mut bytes = vec![];
+            let mut chars = text.bytes();
+            while let Some(b) = chars.next() {
+                match b {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [chars.next()?, chars.next()?];
+                        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                    }
+                    b => bytes.push(b),
+                }
+            }
+            bytes
+        }
+        _ => return None,
+    };
+    // RFC 2231 allows a language after the charset.
+    let label = charset.split('*').next()?;
+    let (decoded, _, _) = encoding_rs::Encoding::for_label(label.as_bytes())?.decode(&bytes);
+    let length = 2 + charset.len() + 1 + encoding.len() + 1 + text.len() + 2;
+    Some((decoded.into_owned(), length))
+}
+
+/// One patch of an uploaded file, with the email headers if it came from `git format-patch`.
+pub struct PatchEmail {
+    pub author: Option<String>,
+    pub date: Option<String>,
+    pub subject: Option<String>,
+    pub body: String,
+    pub files: Vec<PatchFile>,
+    /// Set once the patch has been committed.
+    pub commit: Option<String>,
+    pub error: Option<String>,
+    diff: Vec<u8>,
+    /// The message as uploaded, kept to store the rest of a series that stopped part way.
+    source: Vec<u8>,
+}
+
+pub struct PatchFile {
+    pub header: String,
+    pub hunks: Vec<PatchHunk>,
+}
+
+pub struct PatchHunk {
+    pub header: String,
+    pub lines: Vec<DiffLineData>,
+    pub applies: bool,
+}
+
+impl PatchEmail {
+    /// Splits an mbox into its messages. A plain patch is a single message.
+    fn split_series(content: &[u8]) -> Vec<&[u8]> {
+        let mut messages = vec![];
+        let mut start = 0;
+        let mut offset = 0;
+        let mut previous_blank = true;
+        for line in content.split_inclusive(|b| *b == b'\n') {
+            if line.starts_with(b"From ") && previous_blank && offset > start {
+                messages.push(&content[start..offset]);
+                start = offset;
+            }
+            previous_blank = line.trim_ascii().is_empty();
+            offset += line.len();
+        }
+        messages.push(&content[start..]);
+        messages
+    }
+
+    fn parse(message: &[u8]) -> Result<Option<PatchEmail>> {
+        let mut lines = message.split_inclusive(|b| *b == b'\n').peekable();
+        let mut headers: Vec<(String, Vec<u8>)> = vec![];
+        let is_email = [&b"From "[..], b"From:", b"Subject:", b"Date:"]
+            .iter()
+            .any(|h| message.starts_with(h));
+        if is_email {
+            if message.starts_with(b"From ") {
+                lines.next();
+            }
+            while let Some(line) = lines.next_if(|l| !l.trim_ascii().is_empty()) {
+                match (line.first(), headers.last_mut()) {
+                    (Some(b' ' | b'\t'), Some((_, value))) => {
+                        value.push(b' ');
+                        value.extend_from_slice(line.trim_ascii());
+                    }
+                    _ => {
+                        if let Some(colon) = line.iter().position(|b| *b == b':') {
+                            headers.push((
+                                String::from_utf8_lossy(&line[..colon]).to_lowercase(),
+                                line[colon + 1..].trim_ascii().to_vec(),
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+        let lines = lines.collect_vec();
+        let Some(start) = lines
+            .iter()
+            .position(|line| line.starts_with(b"diff ") || line.starts_with(b"--- "))
+        else {
+            return Ok(None);
+        };
+        // The diff is kept byte for byte, as it may not be UTF-8 and may use CRLF line endings.
+        let diff = lines[start..start + diff_length(&lines[start..])].concat();
+        let body = lines[..start]
+            .iter()
+            .map(|line| String::from_utf8_lossy(line).trim_end().to_string())
+            .collect_vec();
+        let body = body
+            .split(|line| *line == "---")
+            .next()
+            .unwrap_or_default()
+            .join("\n");
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| decode_header(&String::from_utf8_lossy(v)))
+        };
+        let subject = header("subject").map(|subject| match subject.strip_prefix('[') {
+            Some(rest) => rest
+                .split_once(']')
+                .map_or(subject.clone(), |(_, s)| s.trim().to_string()),
+            None => subject,
+        });
+        let parsed = Diff::from_buffer(&diff)?;
+        let mut files = vec![];
+        for idx in 0..parsed.deltas().len() {
+            let Some(patch) = git2::Patch::from_diff(&parsed, idx)? else {
+                continue;
+            };
+            let mut hunks = vec![];
+            for hunk_idx in 0..patch.num_hunks() {
+                let (hunk, line_count) = patch.hunk(hunk_idx)?;
+                let lines = (0..line_count)
+                    .map(|i| {
+                        patch.line_in_hunk(hunk_idx, i).map(|line| DiffLineData {
+                            content: String::from_utf8_lossy(line.content())
+                                .trim_end()
+                                .to_string(),
+                            file_path: None,
+                            operation: line.origin_value(),
+                            old_line_number: line.old_lineno(),
+                            new_line_number: line.new_lineno(),
+                            segments: vec![],
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                hunks.push(PatchHunk {
+                    header: String::from_utf8_lossy(hunk.header())
+                        .trim_end()
+                        .to_string(),
+                    lines,
+                    applies: true,
+                });
+            }
+            files.push(PatchFile {
+                header: file_header(&patch.delta()),
+                hunks,
+            });
+        }
+        Ok(Some(PatchEmail {
+            author: header("from"),
+            date: header("date"),
+            subject,
+            body: body.trim().to_string(),
+            files,
+            commit: None,
+            error: None,
+            diff,
+            source: message.to_vec(),
+        }))
+    }
+
+    pub fn failed_hunks(&self) -> usize {
+        self.files
+            .iter()
+            .flat_map(|f| f.hunks.iter())
+            .filter(|h| !h.applies)
+            .count()
+    }
+
+    fn set_applies(&mut self, applies: &[bool]) {
+        self.files
+            .iter_mut()
+            .flat_map(|f| f.hunks.iter_mut())
+            .zip(applies)
+            .for_each(|(hunk, applies)| hunk.applies = *applies);
+    }
+
+    fn commit_message(&self) -> String {
+        match (&self.subject, self.body.is_empty()) {
+            (Some(subject), true) => subject.clone(),
+            (Some(subject), false) => format!("{}\n\n{}", subject, self.body),
+            (None, _) => "Apply patch".to_string(),
+        }
+    }
+
+    fn signature(&self) -> Option<git2::Signature<'static>> {
+        let (name, email) = self.author.as_ref()?.rsplit_once('<')?;
+        let email = email.trim_end_matches('>');
+        match self
+            .date
+            .as_ref()
+            .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+        {
+            Some(date) => git2::Signature::new(
+                name.trim(),
+                email,
+                &Time::new(date.timestamp(), date.offset().local_minus_utc() / 60),
+            )
+            .ok(),
+            None => git2::Signature::now(name.trim(), email).ok(),
+        }
+    }
+}
+
+impl GitWrapper {
+    pub fn new(repo: &str) -> Result<Self, git2::Error> {
+        let repo = Repository::open(repo)?;
+        Ok(Self {
+            repo,
+            last_commits: RefCell::new(LastCommitCache::default()),
+        })
+    }
+
+    pub fn inner(&self) -> &Repository {
+        &self.repo
+    }
+
+    pub fn get_current_branch(&self) -> Result<String, git2::Error> {
+        Ok(self
+            .repo
+            .head()?
+            .shorthand()
+            .ok_or(git2::Error::from_str("Invalid utf-8 branch name"))?
+            .to_owned())
+    }
+
+    pub fn list_local_branches(&self) -> Result<Vec<String>> {
+        Ok(self
+            .repo
+            .branches(Some(BranchType::Local))?
+            .filter_map(|b| match b.ok()?.0.name() {
+                Ok(Some(name)) => Some(name.to_owned()),
+                _ => None,
+            })
+            .collect::<Vec<String>>())
+    }
+
+    pub fn list_tags(&self) -> Result<Vec<String>, git2::Error> {
+        Ok(self
+            .repo
+            .tag_names(None)?
+            .iter()
+            .flatten()
+            .map(|tag| tag.to_owned())
+            .collect())
+    }
+
+    pub fn list_remotes(&self) -> Result<Vec<String>, git2::Error> {
+        Ok(self
+            .repo
+            .remotes()?
+            .into_iter()
+            .flat_map(|r| r.map(|r| r.to_string()))
+            .collect())
+    }
+
+    pub fn list_remote_branches(&self, remote: &str) -> Result<Vec<String>, git2::Error> {
+        let mut remote = self.repo.find_remote(remote)?;
+        remote.connect(git2::Direction::Fetch)?;
+        Ok(remote
+            .list()?
+            .iter()
+            .map(|head| head.name())
+            .filter(|head_name| head_name.starts_with("refs/heads/"))
+            .map(|head_name| head_name.replace("refs/heads/", ""))
+            .collect())
+    }
+
+    /// Resolves a branch, tag, abbreviated sha or revspec such as `HEAD~3` to the id of the
+    /// commit it points to.
+    pub fn resolve_commit(&self, rev: &str) -> Result<String, git2::Error> {
+        Ok(self.commit_at(rev)?.id().to_string())
+    }
+
+    /// Looks up a revision with `revparse`. A name that could mean more than one ref or object
+    /// is reported as ambiguous instead of silently picking one, as git only warns about it.
+    fn commit_at(&self, rev: &str) -> Result<git2::Commit<'_>, git2::Error> {
+        // Full shas are what most links use, and looking them up directly skips the ref checks.
+        if rev.len() == 40 {
+            if let Ok(id) = Oid::from_str(rev) {
+                return self.repo.find_commit(id);
+            }
+        }
+        let matches = self.revision_matches(rev);
+        if matches.len() > 1 {
+            return Err(git2::Error::new(
+                ErrorCode::Ambiguous,
+                ErrorClass::Reference,
+                format!(
+                    "'{}' is ambiguous, it could be {}. Use one of these names instead.",
+                    rev,
+                    matches.join(" or ")
+                ),
+            ));
+        }
+        let object = self
+            .repo
+            .revparse_single(rev)
+            .map_err(|err| match err.code() {
+                ErrorCode::Ambiguous => git2::Error::new(
+                    ErrorCode::Ambiguous,
+                    err.class(),
+                    format!(
+                        "'{}' is ambiguous, more than one object starts with it. Use a longer sha.",
+                        rev
+                    ),
+                ),
+                ErrorCode::NotFound | ErrorCode::InvalidSpec => git2::Error::new(
+                    ErrorCode::NotFound,
+                    err.class(),
+                    format!("Unknown revision '{}': {}", rev, err.message()),
+                ),
+                _ => err,
+            })?;
+        object.peel_to_commit()
+    }
+
+    /// Everything a plain name could refer to, in the order git looks them up: refs by full
+    /// name, tags, branches, remote branches, then objects whose sha starts with the name.
+    fn revision_matches(&self, name: &str) -> Vec<String> {
+        if name.is_empty() || name.contains(['~', '^', ':', '@', '{']) {
+            return vec![];
+        }
+        let mut matches: Vec<String> = [
+            name.to_string(),
+            format!("refs/{}", name),
+            format!("refs/tags/{}", name),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+            format!("refs/remotes/{}/HEAD", name),
+        ]
+        .into_iter()
+        .filter(|candidate| self.repo.find_reference(candidate).is_ok())
+        .collect();
+        if name.len() >= 4 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+            if let Ok(object) = self.repo.find_object_by_prefix(name, None) {
+                matches.push(object.id().to_string());
+            }
+        }
+        matches
+    }
+
+    pub fn find_commit(&self, sha: &str) -> Result<Commit, git2::Error> {
+        let commit = self.commit_at(sha)?;
+        let summary = commit.summary().map(|v| v.to_string());
+        let body = commit.body().map(|v| v.to_string());
+        let author = commit.author().to_string();
+        Ok(Commit {
+            id: commit.id().to_string(),
+            summary,
+            body,
+            author,
+            date: CommitDate(commit.time()),
+            parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+            sort_score: None,
+        })
+    }
+
+    pub fn commit_diff(
+        &self,
+        sha: &str,
+        settings: &DiffSettings,
+    ) -> Result<Vec<DiffFileItem>, git2::Error> {
+        let diff = self.diff_for_commit(sha, settings)?;
+        let mut total_bytes = 0;
+        let mut result = Vec::new();
+        for idx in 0..diff.deltas().len() {
+            let placeholder = || {
+                diff.get_delta(idx)
+                    .map(|delta| self.diff_file_placeholder(&delta))
+                    .ok_or(git2::Error::from_str("Could not find file in diff."))
+            };
+            // Past the file limit only the names are shown, so the patches are not even built.
+            if idx >= settings.max_files {
+                let mut file = placeholder()?;
+                file.truncated = Some(Truncation::TooManyFiles);
+                result.push(file);
+                continue;
+            }
+            let mut patch = git2::Patch::from_diff(&diff, idx)?;
+            let (context, insertions, deletions) = match &patch {
+                Some(patch) => patch.line_stats()?,
+                None => (0, 0, 0),
+            };
+            let size = patch.as_ref().map_or(0, |p| p.size(true, true, true));
+            let truncated = if context + insertions + deletions > settings.max_file_lines {
+                Some(Truncation::TooManyLines)
+            } else if total_bytes + size > settings.max_bytes {
+                Some(Truncation::TooManyBytes)
+            } else {
+                None
+            };
+            let mut file = match (truncated, patch.as_mut()) {
+                (None, Some(patch)) => {
+                    total_bytes += size;
+                    self.diff_file_item(patch)?
+                }
+                _ => placeholder()?,
+            };
+            file.truncated = truncated;
+            file.insertions = insertions;
+            file.deletions = deletions;
+            result.push(file);
+        }
+        Ok(result)
+    }
+
+    /// The complete diff of a single file in a commit, regardless of the size limits.
+    pub fn commit_diff_file(
+        &self,
+        sha: &str,
+        path: &str,
+        settings: &DiffSettings,
+    ) -> Result<DiffFileItem, git2::Error> {
+        let diff = self.diff_for_commit(sha, settings)?;
+        let idx = diff
+            .deltas()
+            .position(|delta| delta.new_file().path() == Some(std::path::Path::new(path)))
+            .ok_or(git2::Error::from_str("Could not find file in diff."))?;
+        match git2::Patch::from_diff(&diff, idx)? {
+            Some(mut patch) => self.diff_file_item(&mut patch),
+            None => Ok(self.diff_file_placeholder(&diff.get_delta(idx).unwrap())),
+        }
+    }
+
+    fn diff_for_commit(&self, sha: &str, settings: &DiffSettings) -> Result<Diff<'_>, git2::Error> {
+        self.diff_for_commit_with(sha, &mut settings.diff_options(), settings.find_options())
+    }
+
+    fn diff_for_commit_with(
+        &self,
+        sha: &str,
+        diff_options: &mut DiffOptions,
+        find_options: Option<git2::DiffFindOptions>,
+    ) -> Result<Diff<'_>, git2::Error> {
+        let commit = self.commit_at(sha)?;
+        let commit_tree = commit.tree()?;
+        // A root commit is diffed against the empty tree.
+        let commit_parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let mut diff = self.repo.diff_tree_to_tree(
+            commit_parent_tree.as_ref(),
+            Some(&commit_tree),
+            Some(diff_options),
+        )?;
+        if let Some(mut find_options) = find_options {
+            diff.find_similar(Some(&mut find_options))?;
+        }
+        Ok(diff)
+    }
+
+    /// A commit as a plain unified diff, like `git diff sha^ sha`.
+    pub fn commit_raw_diff(&self, sha: &str) -> Result<Vec<u8>, git2::Error> {
+        let diff = self.diff_for_commit(sha, &DiffSettings::default())?;
+        let mut result = vec![];
+        diff.print(DiffFormat::Patch, |_, _, line| {
+            if let '+' | '-' | ' ' = line.origin() {
+                result.push(line.origin() as u8);
+            }
+            result.extend_from_slice(line.content());
+            true
+        })?;
+        Ok(result)
+    }
+
+    /// A commit as a `git format-patch` style email.
+    pub fn commit_patch(&self, sha: &str) -> Result<Vec<u8>, git2::Error> {
+        self.format_patches(&[self.commit_at(sha)?.id()])
+    }
+
+    /// The commits on `head` that are not on `base`, as an mbox series that `git am` can apply.
+    pub fn compare_mbox(&self, base: &str, head: &str) -> Result<Vec<u8>, git2::Error> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        revwalk.push(self.commit_at(head)?.id())?;
+        revwalk.hide(self.commit_at(base)?.id())?;
+        let ids = revwalk
+            .filter_ok(|id| {
+                self.repo
+                    .find_commit(*id)
+                    .is_ok_and(|commit| commit.parent_count() <= 1)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.format_patches(&ids)
+    }
+
+    fn format_patches(&self, ids: &[Oid]) -> Result<Vec<u8>, git2::Error> {
+        let mut result = vec![];
+        for (idx, id) in ids.iter().enumerate() {
+            let commit = self.repo.find_commit(*id)?;
+            // Like format-patch, include binary changes so the series applies with `git am`.
+            let settings = DiffSettings::default();
+            let diff = self.diff_for_commit_with(
+                &id.to_string(),
+                settings.diff_options().show_binary(true),
+                settings.find_options(),
+            )?;
+            let email = git2::Email::from_diff(
+                &diff,
+                idx + 1,
+                ids.len(),
+                id,
+                commit.summary().unwrap_or(""),
+                commit.body().unwrap_or(""),
+                &commit.author(),
+                &mut git2::EmailCreateOptions::new(),
+            )?;
+            result.extend_from_slice(email.as_slice());
+        }
+        Ok(result)
+    }
+
+    /// A file without any hunks, shown for files that are binary or too large to render.
+    fn diff_file_placeholder(&self, delta: &DiffDelta) -> DiffFileItem {
+        let file_path = delta
+            .new_file()
+            .path()
+            .map(|p| p.to_str().unwrap_or("").to_owned());
+        DiffFileItem {
+            generated: self.is_generated(file_path.as_deref().unwrap_or("")),
+            file_diff: DiffLineData {
+                content: file_header(delta),
+                file_path,
+                operation: DiffLineType::FileHeader,
+                old_line_number: None,
+                new_line_number: None,
+                segments: vec![],
+            },
+            hunks: vec![],
+            context_after: None,
+            insertions: 0,
+            deletions: 0,
+            binary: delta.flags().is_binary(),
+            truncated: None,
+            old_blob: self.diff_blob(&delta.old_file()),
+            new_blob: self.diff_blob(&delta.new_file()),
+            mime_type: mime_guess::from_path(
+                delta.new_file().path().unwrap_or(std::path::Path::new("")),
+            )
+            .first_or_octet_stream()
+            .to_string(),
+        }
+    }
+
+    fn diff_blob(&self, file: &git2::DiffFile) -> Option<DiffBlob> {
+        if file.id().is_zero() {
+            return None;
+        }
+        let size = match self.repo.odb().and_then(|odb| odb.read_header(file.id())) {
+            Ok((size, _)) => size as u64,
+            Err(_) => file.size(),
+        };
+        Some(DiffBlob {
+            id: file.id().to_string(),
+            path: file.path()?.to_string_lossy().to_string(),
+            size,
+        })
+    }
+
+    fn diff_file_item(&self, patch: &mut git2::Patch) -> Result<DiffFileItem, git2::Error> {
+        let mut file = self.diff_file_placeholder(&patch.delta());
+        let mut hunk_ranges = std::collections::VecDeque::new();
+        patch.print(&mut |_, hunk, line| {
+            match line.origin_value() {
+                DiffLineType::FileHeader => return true,
+                DiffLineType::Binary => {
+                    file.binary = true;
+                    return true;
+                }
+                DiffLineType::HunkHeader => {
+                    if let Some(hunk) = hunk {
+                        hunk_ranges.push_back((
+                            hunk.old_start(),
+                            hunk.old_lines(),
+                            hunk.new_start(),
+                            hunk.new_lines(),
+                        ))
+                    }
+                }
+                _ => (),
+            }
+            let l = DiffLineData {
+                content: std::str::from_utf8(line.content())
+                    .unwrap_or("FAILED TO PARSE")
+                    .trim_end()
+                    .to_string(),
+                file_path: None,
+                operation: line.origin_value(),
+                old_line_number: line.old_lineno(),
+                new_line_number: line.new_lineno(),
+                segments: vec![],
+            };
+            match l.operation {
+                DiffLineType::HunkHeader => {
+                    let (old_start, old_lines, new_start, new_lines) =
+                        hunk_ranges.pop_front().unwrap_or_default();
+                    file.hunks.push(DiffHunkItem {
+                        hunk_diff: l,
+                        lines: vec![],
+                        old_start,
+                        old_lines,
+                        new_start,
+                        new_lines,
+                        context_before: None,
+                    })
+                }
+                _ => {
+                    match l.operation {
+                        DiffLineType::Addition => file.insertions += 1,
+                        DiffLineType::Deletion => file.deletions += 1,
+                        _ => (),
+                    }
+                    if let Some(hunk) = file.hunks.last_mut() {
+                        hunk.lines.push(l)
+                    }
+                }
+            }
+            true
+        })?;
+        file.hunks
+            .iter_mut()
+            .for_each(|hunk| hunk.highlight_changes());
+        self.find_context_gaps(&mut file, patch.delta().new_file().id());
+        self.highlight_diff(&mut file);
+        Ok(file)
+    }
+
+    /// Highlights both sides of a file in full and styles each diff line from the matching line,
+    /// so constructs spanning hunk boundaries such as block comments stay correct.
+    fn highlight_diff(&self, file: &mut DiffFileItem) {
+        if file.binary {
+            return;
+        }
+        let path = file.path().to_string();
+        let old = file
+            .old_blob
+            .as_ref()
+            .and_then(|blob| self.highlight_blob(&path, &blob.id));
+        let new = file
+            .new_blob
+            .as_ref()
+            .and_then(|blob| self.highlight_blob(&path, &blob.id));
+        for line in file.hunks.iter_mut().flat_map(|hunk| hunk.lines.iter_mut()) {
+            let highlighted = match line.operation {
+                DiffLineType::Deletion => old.as_ref().zip(line.old_line_number),
+                DiffLineType::Addition | DiffLineType::Context => {
+                    new.as_ref().zip(line.new_line_number)
+                }
+                _ => None,
+            };
+            if let Some(tokens) =
+                highlighted.and_then(|(lines, n)| lines.get((n as usize).checked_sub(1)?))
+            {
+                apply_highlighting(line, tokens);
+            }
+        }
+    }
+
+    fn syntax_for(&self, path: &str, content: &str) -> Option<&'static SyntaxReference> {
+        let language = self
+            .repo
+            .get_attr(
+                std::path::Path::new(path),
+                "linguist-language",
+                git2::AttrCheckFlags::default(),
+            )
+            .ok()
+            .flatten()
+            .map(str::to_string);
+        highlight::find_syntax(path, content, language.as_deref())
+    }
+
+    fn highlight_blob(&self, path: &str, id: &str) -> Option<Vec<Vec<Token>>> {
+        let blob = self.repo.find_blob(Oid::from_str(id).ok()?).ok()?;
+        if blob.is_binary() {
+            return None;
+        }
+        let content = std::str::from_utf8(blob.content()).ok()?;
+        highlight::highlight(content, self.syntax_for(path, content)?)
+    }
+
+    /// Whether a file is marked `linguist-generated` in .gitattributes or looks like a lock file
+    /// or build output.
+    fn is_generated(&self, path: &str) -> bool {
+        let marked = self
+            .repo
+            .get_attr(
+                std::path::Path::new(path),
+                "linguist-generated",
+                git2::AttrCheckFlags::default(),
+            )
+            .ok()
+            .flatten()
+            .map(|value| git2::AttrValue::from_string(Some(value)));
+        match marked {
+            Some(git2::AttrValue::True) => return true,
+            Some(git2::AttrValue::False) => return false,
+            Some(git2::AttrValue::String(value)) => return value != "false",
+            _ => (),
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        GENERATED_PATTERNS
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some(suffix) => name.ends_with(suffix),
+                None => name == *pattern,
+            })
+    }
+
+    /// Records the unchanged lines hidden before each hunk and after the last one, so they can
+    /// be expanded on demand.
+    fn find_context_gaps(&self, file: &mut DiffFileItem, new_blob: Oid) {
+        let mut next_line = 1;
+        let mut old_offset = 0;
+        for hunk in file.hunks.iter_mut() {
+            // A hunk without lines on one side starts after the given line rather than at it.
+            let last_hidden = match hunk.new_lines {
+                0 => hunk.new_start,
+                _ => hunk.new_start - 1,
+            };
+            if last_hidden >= next_line {
+                hunk.context_before = Some(ContextGap {
+                    start: next_line,
+                    end: Some(last_hidden),
+                    old_offset,
+                });
+            }
+            next_line = last_hidden + hunk.new_lines + 1;
+            let old_next_line = match hunk.old_lines {
+                0 => hunk.old_start + 1,
+                _ => hunk.old_start + hunk.old_lines,
+            };
+            old_offset = old_next_line as i64 - next_line as i64;
+        }
+        let line_count = match new_blob.is_zero() {
+            true => 0,
+            false => self
+                .repo
+                .find_blob(new_blob)
+                .map(|blob| {
+                    let content = blob.content();
+                    let newlines = content.iter().filter(|b| **b == b'\n').count();
+                    (newlines + usize::from(!content.is_empty() && !content.ends_with(b"\n")))
+                        as u32
+                })
+                .unwrap_or(0),
+        };
+        if !file.hunks.is_empty() && next_line <= line_count {
+            file.context_after = Some(ContextGap {
+                start: next_line,
+                end: None,
+                old_offset,
+            });
+        }
+    }
+
+    /// Reads unchanged lines from a file at a commit, numbered for display as diff context.
+    pub fn context_lines(
+        &self,
+        sha: &str,
+        path: &str,
+        gap: &ContextGap,
+        direction: ExpandDirection,
+        step: u32,
+    ) -> Result<(Vec<DiffLineData>, Option<ContextGap>)> {
+        let content = self.commit_file_content(sha, path)?;
+        let lines = content.lines().collect_vec();
+        let highlighted = self
+            .syntax_for(path, &content)
+            .and_then(|syntax| highlight::highlight(&content, syntax));
+        let (range, remaining) = gap.expand(direction, step, lines.len() as u32);
+        let context = range
+            .filter_map(|n| {
+                let mut line = DiffLineData {
+                    content: lines
+                        .get((n as usize).checked_sub(1)?)?
+                        .trim_end()
+                        .to_string(),
+                    file_path: None,
+                    operation: DiffLineType::Context,
+                    old_line_number: (n as i64)
+                        .checked_add(gap.old_offset)
+                        .and_then(|n| u32::try_from(n).ok()),
+                    new_line_number: Some(n),
+                    segments: vec![],
+                };
+                if let Some(tokens) = highlighted
+                    .as_ref()
+                    .and_then(|h| h.get((n as usize).checked_sub(1)?))
+                {
+                    apply_highlighting(&mut line, tokens);
+                }
+                Some(line)
+            })
+            .collect();
+        Ok((context, remaining))
+    }
+
+    pub fn commit_file_content(&self, sha: &str, path: &str) -> Result<String, git2::Error> {
+        let blob = self.commit_blob(sha, path)?;
+        let (content, _) = self
+            .decode_blob(path, blob.content())
+            .ok_or(git2::Error::from_str("The file is binary or not text"))?;
+        Ok(content)
+    }
+
+    fn commit_blob(&self, sha: &str, path: &str) -> Result<git2::Blob<'_>, git2::Error> {
+        let commit = self.commit_at(sha)?;
+        let tree = commit.tree()?;
+        let entry = tree.get_path(std::path::Path::new(path))?;
+        entry
+            .to_object(&self.repo)?
+            .into_blob()
+            .map_err(|_| git2::Error::from_str("Unable to get blob from object"))
+    }
+
+    /// Decodes a blob as text, returning the name of the encoding used, or `None` if it looks
+    /// binary. Byte order marks win, then UTF-8, then a `working-tree-encoding` attribute, then
+    /// a guess from the content.
+    fn decode_blob(&self, path: &str, bytes: &[u8]) -> Option<(String, &'static str)> {
+        if let Some((encoding, _)) = encoding_rs::Encoding::for_bom(bytes) {
+            let (content, _) = encoding.decode_with_bom_removal(bytes);
+            return Some((content.into_owned(), encoding.name()));
+        }
+        // The same check git uses to decide whether a file is binary.
+        if bytes.iter().take(8000).any(|b| *b == 0) {
+            return None;
+        }
+        if let Ok(content) = std::str::from_utf8(bytes) {
+            return Some((content.to_string(), encoding_rs::UTF_8.name()));
+        }
+        let encoding = self
+            .repo
+            .get_attr(
+                std::path::Path::new(path),
+                "working-tree-encoding",
+                git2::AttrCheckFlags::default(),
+            )
+            .ok()
+            .flatten()
+            .and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()))
+            .unwrap_or_else(|| {
+                let mut detector = chardetng::EncodingDetector::new();
+                detector.feed(bytes, true);
+                detector.guess(None, true)
+            });
+        let (content, _, _) = encoding.decode(bytes);
+        Some((content.into_owned(), encoding.name()))
+    }
+
+    /// The blob id and size of a file at a revision, read without loading its content.
+    pub fn raw_file_header(&self, rev: &str, path: &str) -> Result<(String, usize), git2::Error> {
+        let entry = self
+            .commit_at(rev)?
+            .tree()?
+            .get_path(std::path::Path::new(path))?;
+        if entry.kind() != Some(ObjectType::Blob) {
+            return Err(git2::Error::new(
+                ErrorCode::NotFound,
+                ErrorClass::Tree,
+                format!("{} is not a file", path),
+            ));
+        }
+        let (size, _) = self.repo.odb()?.read_header(entry.id())?;
+        Ok((entry.id().to_string(), size))
+    }
+
+    /// The bytes of the blob `id`, found at `path`, with a MIME type guessed from its extension,
+    /// or from its content when the extension is unknown.
+    pub fn raw_file(&self, id: &str, path: &str) -> Result<RawFile, git2::Error> {
+        let blob = self.repo.find_blob(Oid::from_str(id)?)?;
+        let content = blob.content();
+        let mime_type = match mime_guess::from_path(path).first() {
+            Some(mime) if mime.type_() == "text" => match self.decode_blob(path, content) {
+                Some((_, encoding)) => format!("{}; charset={}", mime, encoding),
+                None => mime.to_string(),
+            },
+            Some(mime) => mime.to_string(),
+            None => match self.decode_blob(path, content) {
+                Some((_, encoding)) => format!("text/plain; charset={}", encoding),
+                None => infer::get(content)
+                    .map_or("application/octet-stream", |kind| kind.mime_type())
+                    .to_string(),
+            },
+        };
+        Ok(RawFile {
+            content: content.to_vec(),
+            mime_type,
+        })
+    }
+
+    /// A file prepared for display: highlighted text if it can be decoded, a hex dump otherwise.
+    pub fn file_blob(&self, sha: &str, path: &str) -> Result<FileBlob, git2::Error> {
+        let blob = self.commit_blob(sha, path)?;
+        let content = match self.decode_blob(path, blob.content()) {
+            Some((content, encoding)) => {
+                let highlighted = self
+                    .syntax_for(path, &content)
+                    .and_then(|syntax| highlight::highlight(&content, syntax));
+                let lines = highlighted.unwrap_or_else(|| {
+                    content
+                        .lines()
+                        .map(|line| {
+                            vec![Token {
+                                text: line.to_string(),
+                                style: String::new(),
+                            }]
+                        })
+                        .collect()
+                });
+                BlobContent::Text { encoding, lines }
+            }
+            None => BlobContent::Binary {
+                rows: hex_dump(&blob.content()[..blob.size().min(HEX_DUMP_BYTES)]),
+                truncated: blob.size() > HEX_DUMP_BYTES,
+            },
+        };
+        Ok(FileBlob {
+            id: blob.id().to_string(),
+            size: blob.size() as u64,
+            content,
+        })
+    }
+
+    pub fn commit_file_tree(&self, sha: &str) -> Result<git2::Tree<'_>, git2::Error> {
+        let commit = self.commit_at(sha)?;
+        commit.tree()
+    }
+
+    pub fn get_file_list_for_commit(
+        &self,
+        sha: &str,
+        path: Option<&str>,
+    ) -> Result<Vec<CommitFile>, git2::Error> {
+        let commit = self.commit_at(sha)?;
+        let tree = commit.tree()?;
+        let obj = match path {
+            Some(path) => tree
+                .get_path(std::path::Path::new(path))?
+                .to_object(&self.repo)?,
+            None => tree.clone().into_object(),
+        };
+        let directory = path.unwrap_or("");
+        let odb = self.repo.odb()?;
+        let submodule_urls = tree
+            .get_path(std::path::Path::new(".gitmodules"))
+            .ok()
+            .and_then(|entry| entry.to_object(&self.repo).ok()?.into_blob().ok())
+            .map(|blob| parse_gitmodules(&String::from_utf8_lossy(blob.content())))
+            .unwrap_or_default();
+        let mut file_list = obj
+            .into_tree()
+            .map_err(|_| git2::Error::from_str("Could not get tree"))?
+            .iter()
+            .map(|t| {
+                let name = t.name().map(|t| t.to_string());
+                let entry_path = match directory {
+                    "" => name.clone().unwrap_or_default(),
+                    directory => format!("{}/{}", directory, name.as_deref().unwrap_or("")),
+                };
+                let (mut size, mut symlink, mut submodule) = (None, None, None);
+                if t.filemode() == i32::from(git2::FileMode::Link) {
+                    let target = self
+                        .repo
+                        .find_blob(t.id())
+                        .map(|blob| String::from_utf8_lossy(blob.content()).to_string())
+                        .unwrap_or_default();
+                    let path = resolve_symlink(directory, &target)
+                        .filter(|path| tree.get_path(std::path::Path::new(path)).is_ok());
+                    symlink = Some(Symlink { target, path });
+                } else if t.filemode() == i32::from(git2::FileMode::Commit) {
+                    submodule = Some(Submodule {
+                        commit: t.id().to_string(),
+                        url: submodule_urls.get(&entry_path).cloned(),
+                    });
+                } else if t.kind() == Some(ObjectType::Blob) {
+                    size = odb.read_header(t.id()).ok().map(|(size, _)| size as u64);
+                }
+                CommitFile {
+                    id: t.id(),
+                    name,
+                    kind: t.kind(),
+                    filemode: t.filemode(),
+                    last_commit: None,
+                    size,
+                    symlink,
+                    submodule,
+                }
+            })
+            .collect_vec();
+        file_list.sort_by_key(|f| match f.kind {
+            Some(ObjectType::Tree) => 0,
+            _ => 1,
+        });
+        Ok(file_list)
+    }
+
+    /// Fills in the last commit of each entry of a directory listing.
+    ///
+    /// On a cache miss this walks the history while the caller holds the repository lock, so
+    /// other requests wait for it. The walk visits each commit at most once. The result is cached
+    /// by commit and tree id, as the same tree can be reached through different histories, so
+    /// only the first view of a directory at a commit pays for it.
+    pub fn annotate_last_commits(
+        &self,
+        sha: &str,
+        path: Option<&str>,
+        files: &mut [CommitFile],
+    ) -> Result<(), git2::Error> {
+        let commit = self.commit_at(sha)?;
+        let tree_id =
+            directory_id(&commit, path).ok_or(git2::Error::from_str("Could not get tree"))?;
+        let key = (commit.id(), tree_id);
+        let mut cache = self.last_commits.borrow_mut();
+        if cache.get(key).is_none() {
+            cache.insert(key, self.find_last_commits(&commit, path)?);
+        }
+        let (_, last_commits) = &cache.entries[&key];
+        for file in files {
+            file.last_commit = file
+                .name
+                .as_ref()
+                .and_then(|name| last_commits.get(name))
+                .cloned();
+        }
+        Ok(())
+    }
+
+    /// Walks the history once, newest first, attributing each entry to the first commit that
+    /// has the entry's current id while none of its parents do. Commits where the directory
+    /// matches a parent's cannot have touched it and are skipped without reading the trees.
+    fn find_last_commits(
+        &self,
+        commit: &git2::Commit<'_>,
+        path: Option<&str>,
+    ) -> Result<HashMap<String, LastCommit>, git2::Error> {
+        let tree = self.repo.find_tree(
+            directory_id(commit, path).ok_or(git2::Error::from_str("Could not get tree"))?,
+        )?;
+        let mut pending: HashMap<String, Oid> = tree
+            .iter()
+            .filter_map(|entry| Some((entry.name()?.to_string(), entry.id())))
+            .collect();
+        let mut found = HashMap::new();
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TIME)?;
+        revwalk.push(commit.id())?;
+        for oid in revwalk {
+            if pending.is_empty() {
+                break;
+            }
+            let commit = self.repo.find_commit(oid?)?;
+            let Some(tree_id) = directory_id(&commit, path) else {
+                continue;
+            };
+            let parent_ids: Vec<Option<Oid>> = commit
+                .parents()
+                .map(|parent| directory_id(&parent, path))
+                .collect();
+            if parent_ids.contains(&Some(tree_id)) {
+                continue;
+            }
+            let tree = self.repo.find_tree(tree_id)?;
+            let parent_trees: Vec<git2::Tree> = parent_ids
+                .into_iter()
+                .flatten()
+                .filter_map(|id| self.repo.find_tree(id).ok())
+                .collect();
+            pending.retain(|name, id| {
+                let touched = tree.get_name(name).map(|entry| entry.id()) == Some(*id)
+                    && !parent_trees
+                        .iter()
+                        .any(|parent| parent.get_name(name).map(|entry| entry.id()) == Some(*id));
+                if touched {
+                    found.insert(name.clone(), LastCommit::from(&commit));
+                }
+                !touched
+            });
+        }
+        Ok(found)
+    }
+
+    /// Finds the README of a directory, preferring Markdown over other formats, and returns its
+    /// path and content.
+    pub fn directory_readme(
+        &self,
+        sha: &str,
+        path: Option<&str>,
+    ) -> Result<Option<(String, String)>, git2::Error> {
+        let files = self.get_file_list_for_commit(sha, path)?;
+        let readme = files
+            .iter()
+            .filter(|file| file.kind == Some(ObjectType::Blob))
+            .filter_map(|file| {
+                let name = file.name.as_deref()?;
+                let (stem, extension) = name.split_once('.').unwrap_or((name, ""));
+                if !stem.eq_ignore_ascii_case("readme") {
+                    return None;
+                }
+                let rank = README_EXTENSIONS
+                    .iter()
+                    .position(|ext| ext.eq_ignore_ascii_case(extension))?;
+                Some((rank, name))
+            })
+            .min();
+        let Some((_, name)) = readme else {
+            return Ok(None);
+        };
+        let readme_path = match path {
+            Some(path) => format!("{}/{}", path.trim_end_matches('/'), name),
+            None => name.to_string(),
+        };
+        let content = self.commit_file_content(sha, &readme_path)?;
+        Ok(Some((readme_path, content)))
+    }
+
+    pub fn checkout_local_branch(&self, branch: &str) -> Result<(), git2::Error> {
+        let branch_ref = &format!("refs/heads/{}", branch);
+        let obj = self.repo.revparse_single(branch_ref)?;
+        self.repo.checkout_tree(&obj, None)?;
+        self.repo.set_head(branch_ref)?;
+        Ok(())
+    }
+
+    pub fn list_commits<'a>(
+        &'a self,
+        reference: &str,
+        filter: Option<&'a str>,
+    ) -> Result<impl Iterator<Item = Commit> + 'a> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        if reference.contains("...") {
+            return Err(git2::Error::new(
+                ErrorCode::Invalid,
+                ErrorClass::Invalid,
+                format!("Invalid revision range {}", reference),
+            )
+            .into());
+        }
+        // Both ends of an `a..b` range go through `commit_at` so that ambiguous names are
+        // rejected. An empty end means HEAD, as in git.
+        match reference.split_once("..") {
+            Some((from, to)) => {
+                let from = if from.is_empty() { "HEAD" } else { from };
+                let to = if to.is_empty() { "HEAD" } else { to };
+                revwalk.push(self.commit_at(to)?.id())?;
+                revwalk.hide(self.commit_at(from)?.id())?;
+            }
+            None => revwalk.push(self.commit_at(reference)?.id())?,
+        }
+
+        let matcher = SkimMatcherV2::default();
+        let result = revwalk.filter_map(move |id| match id {
+            Ok(id) => match (filter, self.repo.find_commit(id)) {
+                (Some(filter), Ok(commit)) => {
+                    let message = commit.message().map(|v| v.to_string());
+                    let summary = commit.summary().map(|v| v.to_string());
+                    let body = commit.body().map(|v| v.to_string());
+                    let score = match message.clone() {
+                        Some(msg) => matcher.fuzzy_match(&msg, filter),
+                        None => None,
+                    };
+                    score.map(move |score| Commit {
+                        id: id.to_string(),
+                        summary,
+                        body,
+                        author: commit.author().to_string(),
+                        date: CommitDate(commit.time()),
+                        parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+                        sort_score: Some(score),
+                    })
+                }
+                (None, Ok(commit)) => Some(Commit {
+                    id: id.to_string(),
+                    summary: commit.summary().map(|v| v.to_string()),
+                    body: commit.body().map(|v| v.to_string()),
+                    author: commit.author().to_string(),
+                    date: CommitDate(commit.time()),
+                    parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+                    sort_score: None,
+                }),
+                _ => None,
+            },
+            _ => None,
+        });
+        match filter {
+            Some(_) => Ok(itertools::Either::Right(result.sorted())),
+            None => Ok(itertools::Either::Left(result)),
+        }
+    }
+
+    pub fn merge_preview(&self, reference: &str) -> Result<MergePreview> {
+        let source = self.commit_at(reference)?;
+        let head = self.repo.head()?.peel_to_commit()?;
+        let annotated = self.repo.find_annotated_commit(source.id())?;
+        let (analysis, _) = self.repo.merge_analysis(&[&annotated])?;
+
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        revwalk.push(source.id())?;
+        revwalk.hide(head.id())?;
+        let commits = revwalk
+            .filter_map(|id| self.find_commit(&id.ok()?.to_string()).ok())
+            .collect_vec();
+
+        let base = match self.repo.merge_base(head.id(), source.id()) {
+            Ok(base) => Some(base),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let conflicts = match (analysis.is_normal(), base) {
+            (true, Some(base)) => {
+                let ancestor = self.repo.find_commit(base)?.tree()?;
+                let index =
+                    self.repo
+                        .merge_trees(&ancestor, &head.tree()?, &source.tree()?, None)?;
+                conflicted_paths(&index)?
+            }
+            _ => vec![],
+        };
+
+        Ok(MergePreview {
+            source_id: source.id().to_string(),
+            unrelated: base.is_none(),
+            up_to_date: analysis.is_up_to_date(),
+            fast_forward: analysis.is_fast_forward(),
+            conflicts,
+            commits,
+        })
+    }
+
+    pub fn merge(&self, reference: &str, mode: MergeMode) -> Result<MergeOutcome> {
+        let source = self.commit_at(reference)?;
+        let annotated = self.repo.find_annotated_commit(source.id())?;
+        let (analysis, _) = self.repo.merge_analysis(&[&annotated])?;
+        if analysis.is_up_to_date() {
+            return Ok(MergeOutcome::UpToDate);
+        }
+
+        let fast_forward_allowed =
+            matches!(mode, MergeMode::FastForward | MergeMode::FastForwardOnly);
+        if analysis.is_fast_forward() && fast_forward_allowed {
+            self.repo.checkout_tree(
+                source.as_object(),
+                Some(git2::build::CheckoutBuilder::new().safe()),
+            )?;
+            let message = format!("merge {}: Fast-forward", reference);
+            match self.repo.head()?.is_branch() {
+                true => {
+                    self.repo.head()?.set_target(source.id(), &message)?;
+                }
+                false => self.repo.set_head_detached(source.id())?,
+            }
+            return Ok(MergeOutcome::FastForwarded(source.id().to_string()));
+        }
+        if mode == MergeMode::FastForwardOnly {
+            return Err(git2::Error::new(
+                git2::ErrorCode::Invalid,
+                git2::ErrorClass::Merge,
+                format!("Not possible to fast-forward to {}, aborting.", reference),
+            )
+            .into());
+        }
+
+        let head = self.repo.head()?.peel_to_commit()?;
+        match self.repo.merge_base(head.id(), source.id()) {
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {
+                return Err(unrelated_histories(&self.get_current_branch()?, reference).into())
+            }
+            result => result?,
+        };
+        self.repo.merge(
+            &[&annotated],
+            None,
+            Some(git2::build::CheckoutBuilder::new().safe()),
+        )?;
+        let message = match mode {
+            MergeMode::Squash => {
+                let message = self.squash_message(head.id(), source.id())?;
+                // A squash merge never records the other side as a parent, so the merge state
+                // is dropped and SQUASH_MSG alone marks the squash as in progress, as git does.
+                self.repo.cleanup_state()?;
+                std::fs::write(self.squash_msg_path(), &message)?;
+                message
+            }
+            _ => format!("Merge {} into {}", reference, self.get_current_branch()?),
+        };
+        let index = self.repo.index()?;
+        if index.has_conflicts() {
+            return Ok(MergeOutcome::Conflicted(conflicted_paths(&index)?));
+        }
+        self.commit_merge(&message).map(MergeOutcome::Committed)
+    }
+
+    /// Lists every commit being squashed in the log format `git merge --squash` uses.
+    fn squash_message(&self, head: Oid, source: Oid) -> Result<String> {
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.push(source)?;
+        revwalk.hide(head)?;
+        let mut message = "Squashed commit of the following:\n".to_string();
+        for id in revwalk {
+            let commit = self.repo.find_commit(id?)?;
+            message.push_str(&format!(
+                "\ncommit {}\nAuthor: {}\nDate:   {}\n\n",
+                commit.id(),
+                commit.author(),
+                CommitDate(commit.time())
+            ));
+            for line in commit.message().unwrap_or("").trim_end().lines() {
+                match line.is_empty() {
+                    true => message.push('\n'),
+                    false => message.push_str(&format!("    {}\n", line)),
+                }
+            }
+        }
+        Ok(message)
+    }
+
+    /// The commit the in-progress merge, cherry-pick or revert is applying, if any.
+    fn operation_head(&self) -> Option<git2::Commit<'_>> {
+        let head_name = match self.repo.state() {
+            git2::RepositoryState::Merge => "MERGE_HEAD",
+            git2::RepositoryState::CherryPick => "CHERRY_PICK_HEAD",
+            git2::RepositoryState::Revert => "REVERT_HEAD",
+            _ => return None,
+        };
+        self.repo
+            .revparse_single(head_name)
+            .ok()?
+            .peel_to_commit()
+            .ok()
+    }
+
+    fn squash_msg_path(&self) -> std::path::PathBuf {
+        self.repo.path().join("SQUASH_MSG")
+    }
+
+    pub fn merge_status(&self) -> Result<MergeStatus> {
+        let squash = std::fs::read_to_string(self.squash_msg_path()).ok();
+        let state = self.repo.state();
+        Ok(MergeStatus {
+            state: match (state, &squash) {
+                (git2::RepositoryState::Clean, Some(_)) => "Squash".to_string(),
+                _ => format!("{:?}", state),
+            },
+            clean: state == git2::RepositoryState::Clean && squash.is_none(),
+            operation_head: self.operation_head().map(|c| c.id().to_string()),
+            message: self.repo.message().ok().or(squash),
+            conflicts: conflicted_paths(&self.repo.index()?)?,
+        })
+    }
+
+    /// Ends any in-progress operation, including a squash merge.
+    fn cleanup_state(&self) -> Result<()> {
+        self.repo.cleanup_state()?;
+        match std::fs::remove_file(self.squash_msg_path()) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Commits the current index as the conclusion of a merge, cherry-pick or revert. A merge
+    /// records `MERGE_HEAD` as the second parent and a cherry-pick keeps the original author.
+    pub fn commit_merge(&self, message: &str) -> Result<String> {
+        let mut index = self.repo.index()?;
+        if index.has_conflicts() {
+            return Err(anyhow::anyhow!(
+                "Resolve all conflicts before concluding the merge"
+            ));
+        }
+        let tree = self.repo.find_tree(index.write_tree()?)?;
+        let head = self.repo.head()?.peel_to_commit()?;
+        let signature = self.repo.signature()?;
+        let mut parents = vec![head];
+        let mut author = signature.clone();
+        match (self.repo.state(), self.operation_head()) {
+            (git2::RepositoryState::Merge, Some(merge_head)) => parents.push(merge_head),
+            (git2::RepositoryState::CherryPick, Some(picked)) => {
+                author = picked.author().to_owned()
+            }
+            _ => (),
+        }
+        let id = self.repo.commit(
+            Some("HEAD"),
+            &author,
+            &signature,
+            message,
+            &tree,
+            &parents.iter().collect_vec(),
+        )?;
+        self.cleanup_state()?;
+        Ok(id.to_string())
+    }
+
+    /// Commits the result of a cherry-pick or revert that has just been applied to the index, or
+    /// leaves the operation in progress with the message saved if it stopped on conflicts.
+    fn finish_pick(&self, message: &str) -> Result<MergeOutcome> {
+        let index = self.repo.index()?;
+        if index.has_conflicts() {
+            std::fs::write(self.repo.path().join("MERGE_MSG"), message)?;
+            return Ok(MergeOutcome::Conflicted(conflicted_paths(&index)?));
+        }
+        let head_tree = self.repo.head()?.peel_to_tree()?;
+        if self.repo.index()?.write_tree()? == head_tree.id() {
+            self.repo.cleanup_state()?;
+            return Err(git2::Error::new(
+                git2::ErrorCode::Invalid,
+                git2::ErrorClass::CherryPick,
+                "The changes are already present, nothing to commit",
+            )
+            .into());
+        }
+        self.commit_merge(message).map(MergeOutcome::Committed)
+    }
+
+    /// Checks that `mainline` names one of the parents of `commit`, counting from 1.
+    fn check_mainline(commit: &git2::Commit, mainline: Option<u32>) -> Result<()> {
+        match mainline {
+            Some(mainline) if mainline == 0 || mainline as usize > commit.parent_count() => {
+                Err(git2::Error::new(
+                    git2::ErrorCode::Invalid,
+                    git2::ErrorClass::Invalid,
+                    format!(
+                        "Mainline {} is not a parent of {}, which has {} parent(s)",
+                        mainline,
+                        commit.id(),
+                        commit.parent_count()
+                    ),
+                )
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn cherry_pick(
+        &self,
+        sha: &str,
+        mainline: Option<u32>,
+        record_origin: bool,
+    ) -> Result<MergeOutcome> {
+        let commit = self.commit_at(sha)?;
+        Self::check_mainline(&commit, mainline)?;
+        let mut options = git2::CherrypickOptions::new();
+        if let Some(mainline) = mainline {
+            options.mainline(mainline);
+        }
+        self.repo.cherrypick(&commit, Some(&mut options))?;
+        let mut message = commit.message().unwrap_or("").trim_end().to_string();
+        if record_origin {
+            message.push_str(&format!("\n\n(cherry picked from commit {})", commit.id()));
+        }
+        self.finish_pick(&message)
+    }
+
+    pub fn revert(&self, sha: &str, mainline: Option<u32>) -> Result<MergeOutcome> {
+        let commit = self.commit_at(sha)?;
+        Self::check_mainline(&commit, mainline)?;
+        let mut options = git2::RevertOptions::new();
+        if let Some(mainline) = mainline {
+            options.mainline(mainline);
+        }
+        self.repo.revert(&commit, Some(&mut options))?;
+        let mut message = format!(
+            "Revert \"{}\"\n\nThis reverts commit {}",
+            commit.summary().unwrap_or(""),
+            commit.id()
+        );
+        match mainline.and_then(|m| commit.parent_id(m as usize - 1).ok()) {
+            Some(parent) => message.push_str(&format!(", reversing\nchanges made to {}.", parent)),
+            None => message.push('.'),
+        }
+        self.finish_pick(&message)
+    }
+
+    pub fn abort_merge(&self) -> Result<()> {
+        let head = self.repo.head()?.peel_to_commit()?;
+        self.repo.reset(
+            head.as_object(),
+            git2::ResetType::Hard,
+            Some(git2::build::CheckoutBuilder::new().force()),
+        )?;
+        self.cleanup_state()
+    }
+
+    pub fn list_conflicts(&self) -> Result<Vec<String>, git2::Error> {
+        conflicted_paths(&self.repo.index()?)
+    }
+
+    fn find_conflict(&self, path: &str) -> Result<git2::IndexConflict> {
+        self.repo
+            .index()?
+            .conflicts()?
+            .filter_map(|c| c.ok())
+            .find(|c| {
+                [&c.our, &c.their, &c.ancestor]
+                    .into_iter()
+                    .flatten()
+                    .any(|entry| entry.path == path.as_bytes())
+            })
+            .ok_or_else(|| {
+                git2::Error::new(
+                    git2::ErrorCode::NotFound,
+                    git2::ErrorClass::Index,
+                    format!("{} is not conflicted", path),
+                )
+                .into()
+            })
+    }
+
+    pub fn conflict_file(&self, path: &str) -> Result<ConflictFile> {
+        let conflict = self.find_conflict(path)?;
+        let blobs = [&conflict.ancestor, &conflict.our, &conflict.their]
+            .into_iter()
+            .map(|entry| match entry {
+                Some(entry) => self.repo.find_blob(entry.id).map(Some),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<_>, git2::Error>>()?;
+        let binary = blobs.iter().flatten().any(|blob| blob.is_binary());
+        let [ancestor, ours, theirs] = [&blobs[0], &blobs[1], &blobs[2]].map(|blob| {
+            blob.as_ref()
+                .map(|blob| String::from_utf8_lossy(blob.content()).to_string())
+        });
+
+        let (merged, segments) = match binary {
+            true => (String::new(), vec![]),
+            false => merge_conflicted(
+                ancestor.as_deref().unwrap_or(""),
+                ours.as_deref().unwrap_or(""),
+                theirs.as_deref().unwrap_or(""),
+            ),
+        };
+        Ok(ConflictFile {
+            path: path.to_string(),
+            binary,
+            segments,
+            merged,
+            ancestor,
+            ours,
+            theirs,
+        })
+    }
+
+    /// Writes the resolved content to the working tree and index, clearing the conflict for the
+    /// path. Passing `None` resolves the conflict by deleting the file.
+    pub fn resolve_conflict(&self, path: &str, content: Option<&[u8]>) -> Result<()> {
+        if path
+            .split('/')
+            .any(|segment| matches!(segment, "" | "." | ".."))
+        {
+            return Err(git2::Error::new(
+                git2::ErrorCode::Invalid,
+                git2::ErrorClass::Index,
+                format!("{} is not a valid path", path),
+            )
+            .into());
+        }
+        self.find_conflict(path)?;
+        let workdir = self.repo.workdir().ok_or(anyhow::anyhow!(
+            "Cannot resolve conflicts in a bare repository"
+        ))?;
+        let file_path = workdir.join(path);
+        let mut index = self.repo.index()?;
+        match content {
+            Some(content) => {
+                if let Some(parent) = file_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&file_path, content)?;
+                index.add_path(std::path::Path::new(path))?;
+            }
+            None => {
+                if file_path.exists() {
+                    std::fs::remove_file(&file_path)?;
+                }
+                index.remove_path(std::path::Path::new(path))?;
+            }
+        }
+        index.write()?;
+        Ok(())
+    }
+
+    /// Resolves a conflict by taking one side of it wholesale, which also covers binary files and
+    /// modify/delete conflicts.
+    pub fn take_conflict_side(&self, path: &str, side: ConflictSide) -> Result<()> {
+        let conflict = self.find_conflict(path)?;
+        let entry = match side {
+            ConflictSide::Ours => conflict.our,
+            ConflictSide::Theirs => conflict.their,
+            ConflictSide::Ancestor => conflict.ancestor,
+            ConflictSide::Both => {
+                return Err(anyhow::anyhow!("Cannot take both sides of a whole file"))
+            }
+        };
+        match entry {
+            Some(entry) => {
+                let blob = self.repo.find_blob(entry.id)?;
+                self.resolve_conflict(path, Some(blob.content()))
+            }
+            None => self.resolve_conflict(path, None),
+        }
+    }
+
+    /// Lists the commits on the current branch that would no longer be reachable from it after
+    /// resetting to `sha`.
+    pub fn unreachable_after_reset(&self, sha: &str) -> Result<Vec<Commit>> {
+        let target = self.commit_at(sha)?;
+        let mut revwalk = self.repo.revwalk()?;
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        revwalk.push_head()?;
+        revwalk.hide(target.id())?;
+        Ok(revwalk
+            .filter_map(|id| self.find_commit(&id.ok()?.to_string()).ok())
+            .collect_vec())
+    }
+
+    pub fn reset(&self, sha: &str, mode: ResetMode) -> Result<(), git2::Error> {
+        let target = self.commit_at(sha)?;
+        self.repo.reset(
+            target.as_object(),
+            mode.into(),
+            Some(git2::build::CheckoutBuilder::new().force()),
+        )
+    }
+
+    /// Moves HEAD back to where it was before the most recent reset, as recorded in the reflog.
+    /// The undo is logged as "undo reset" so that undoing again does not redo the reset.
+    pub fn undo_reset(&self, mode: ResetMode) -> Result<String> {
+        let reflog = self.repo.reflog("HEAD")?;
+        let entry = reflog
+            .get(0)
+            .filter(|e| e.message().unwrap_or("").starts_with("reset: moving to"))
+            .ok_or(git2::Error::new(
+                git2::ErrorCode::Invalid,
+                git2::ErrorClass::Reference,
+                "The last change to HEAD was not a reset",
+            ))?;
+        let previous = entry.id_old().to_string();
+        self.reset(&previous, mode)?;
+        let message = format!("undo reset: moving to {}", previous);
+        self.reword_last_reflog_entry("HEAD", &message)?;
+        if let Some(branch) = self.repo.head()?.name().filter(|name| *name != "HEAD") {
+            self.reword_last_reflog_entry(branch, &message)?;
+        }
+        Ok(previous)
+    }
+
+    fn reword_last_reflog_entry(&self, reference: &str, message: &str) -> Result<(), git2::Error> {
+        let mut reflog = self.repo.reflog(reference)?;
+        let Some((id, committer)) = reflog
+            .get(0)
+            .map(|entry| (entry.id_new(), entry.committer().to_owned()))
+        else {
+            return Ok(());
+        };
+        reflog.remove(0, false)?;
+        reflog.append(id, &committer, Some(message))?;
+        reflog.write()
+    }
+
+    pub fn reflog(&self, reference: &str) -> Result<Vec<ReflogEntry>, git2::Error> {
+        let reference = match reference {
+            "HEAD" => "HEAD".to_string(),
+            _ => self
+                .repo
+                .resolve_reference_from_short_name(reference)?
+                .name()
+                .ok_or(git2::Error::from_str("Invalid utf-8 reference name"))?
+                .to_owned(),
+        };
+        Ok(self
+            .repo
+            .reflog(&reference)?
+            .iter()
+            .map(|entry| ReflogEntry {
+                old_id: match entry.id_old().is_zero() {
+                    true => None,
+                    false => Some(entry.id_old().to_string()),
+                },
+                new_id: entry.id_new().to_string(),
+                message: entry.message().map(|m| m.to_string()),
+                committer: entry.committer().to_string(),
+                date: CommitDate(entry.committer().when()),
+            })
+            .collect())
+    }
+
+    pub fn create_branch(&self, name: &str, sha: &str) -> Result<(), git2::Error> {
+        let commit = self.commit_at(sha)?;
+        self.repo.branch(name, &commit, false)?;
+        Ok(())
+    }
+
+    fn rebase_options<'a>() -> git2::RebaseOptions<'a> {
+        let mut options = git2::RebaseOptions::new();
+        options.inmemory(false);
+        options
+    }
+
+    /// Rebases the current branch onto `upstream` with git's own rebase machinery.
+    pub fn rebase(&self, upstream: &str) -> Result<MergeOutcome> {
+        let mut rebase = self.start_rebase(upstream)?;
+        self.run_rebase(&mut rebase, None)
+    }
+
+    fn start_rebase(&self, upstream: &str) -> Result<git2::Rebase<'_>> {
+        self.repo.index()?.read(false)?;
+        if !self.repo.head()?.is_branch() {
+            return Err(anyhow::anyhow!("Cannot rebase a detached HEAD"));
+        }
+        let upstream = self.commit_at(upstream)?;
+        let upstream = self.repo.find_annotated_commit(upstream.id())?;
+        Ok(self.repo.rebase(
+            None,
+            Some(&upstream),
+            None,
+            Some(&mut Self::rebase_options()),
+        )?)
+    }
+
+    /// Starts an interactive rebase of the current branch onto `upstream`, applying `steps` in
+    /// order. The steps must cover every commit the rebase replays, exactly once.
+    pub fn rebase_interactive(
+        &self,
+        upstream: &str,
+        steps: Vec<RebaseStep>,
+    ) -> Result<MergeOutcome> {
+        let ids = steps
+            .iter()
+            .map(|step| Oid::from_str(&step.id))
+            .collect::<Result<Vec<_>, git2::Error>>()?;
+        let first = steps.iter().find(|step| step.action != RebaseAction::Drop);
+        if let Some(RebaseAction::Squash | RebaseAction::Fixup) = first.map(|step| step.action) {
+            return Err(anyhow::anyhow!(
+                "Cannot squash or fixup without a previous commit"
+            ));
+        }
+
+        let mut rebase = self.start_rebase(upstream)?;
+        let operations = (0..rebase.len())
+            .filter_map(|i| rebase.nth(i).map(|operation| operation.id()))
+            .sorted()
+            .collect_vec();
+        if operations != ids.iter().copied().sorted().collect_vec() {
+            rebase.abort()?;
+            return Err(anyhow::anyhow!(
+                "The plan does not match the commits to rebase, reload the planner"
+            ));
+        }
+        drop(rebase);
+
+        // The rebase API has no way to drop or reorder commits, but libgit2 replays the commits
+        // listed in its state directory, so rewriting that list is enough. This relies on the
+        // `rebase-merge` layout, which libgit2 shares with git but does not document.
+        let dir = self.repo.path().join(REBASE_DIR);
+        let mut plan = String::new();
+        let kept = steps
+            .iter()
+            .zip(ids)
+            .filter(|(step, _)| step.action != RebaseAction::Drop)
+            .collect_vec();
+        for (i, (step, id)) in kept.iter().enumerate() {
+            std::fs::write(dir.join(format!("cmt.{}", i + 1)), format!("{}\n", id))?;
+            plan.push_str(&format!("{} {}\n", step.action, id));
+            if let Some(message) = &step.message {
+                std::fs::write(dir.join(format!("message-{}", id)), message)?;
+            }
+        }
+        for i in kept.len()..steps.len() {
+            std::fs::remove_file(dir.join(format!("cmt.{}", i + 1)))?;
+        }
+        std::fs::write(dir.join("end"), format!("{}\n", kept.len()))?;
+        std::fs::write(dir.join(REBASE_PLAN), plan)?;
+
+        let mut rebase = self.repo.open_rebase(Some(&mut Self::rebase_options()))?;
+        let plan = self.rebase_plan()?;
+        self.run_rebase(&mut rebase, plan.as_deref())
+    }
+
+    /// The steps of the interactive rebase in progress, in the order of its operations.
+    fn rebase_plan(&self) -> Result<Option<Vec<RebaseStep>>> {
+        let dir = self.repo.path().join(REBASE_DIR);
+        let plan = match std::fs::read_to_string(dir.join(REBASE_PLAN)) {
+            Ok(plan) => plan,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        plan.lines()
+            .map(|line| {
+                let (action, id) = line
+                    .split_once(' ')
+                    .ok_or(anyhow::anyhow!("Invalid rebase plan line: {}", line))?;
+                let id = Oid::from_str(id)?;
+                Ok(RebaseStep {
+                    action: action.parse()?,
+                    message: std::fs::read_to_string(dir.join(format!("message-{}", id))).ok(),
+                    id: id.to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    fn run_rebase(
+        &self,
+        rebase: &mut git2::Rebase,
+        plan: Option<&[RebaseStep]>,
+    ) -> Result<MergeOutcome> {
+        while let Some(operation) = rebase.next() {
+            operation?;
+            let index = self.repo.index()?;
+            if index.has_conflicts() {
+                return Ok(MergeOutcome::Conflicted(conflicted_paths(&index)?));
+            }
+            self.commit_operation(rebase, plan)?;
+        }
+        rebase.finish(Some(&self.repo.signature()?))?;
+        Ok(MergeOutcome::Committed(
+            self.repo.head()?.peel_to_commit()?.id().to_string(),
+        ))
+    }
+
+    /// Commits the index for the current operation of a rebase, folding it into HEAD for squash
+    /// and fixup steps. Operations that turn out to change nothing are left out. libgit2 cannot
+    /// squash, so those steps amend HEAD directly and are missing from its list of rewritten
+    /// commits.
+    fn commit_operation(
+        &self,
+        rebase: &mut git2::Rebase,
+        plan: Option<&[RebaseStep]>,
+    ) -> Result<()> {
+        let Some(current) = rebase.operation_current() else {
+            return Ok(());
+        };
+        let step = plan.and_then(|plan| plan.get(current));
+        let signature = self.repo.signature()?;
+        let operation_type = step.map_or(Some(git2::RebaseOperationType::Pick), |step| {
+            step.action.operation_type()
+        });
+        let result = match operation_type {
+            Some(git2::RebaseOperationType::Squash | git2::RebaseOperationType::Fixup) => {
+                let id = rebase
+                    .nth(current)
+                    .ok_or(anyhow::anyhow!("Missing rebase operation {}", current))?
+                    .id();
+                let commit = self.repo.find_commit(id)?;
+                let head = self.repo.head()?.peel_to_commit()?;
+                let tree = self.repo.find_tree(self.repo.index()?.write_tree()?)?;
+                let message = match operation_type {
+                    Some(git2::RebaseOperationType::Squash) => format!(
+                        "{}\n\n{}",
+                        head.message().unwrap_or("").trim_end(),
+                        commit.message().unwrap_or("")
+                    ),
+                    _ => head.message().unwrap_or("").to_string(),
+                };
+                head.amend(
+                    Some("HEAD"),
+                    None,
+                    Some(&signature),
+                    None,
+                    Some(&message),
+                    Some(&tree),
+                )
+                .map(|_| ())
+            }
+            Some(git2::RebaseOperationType::Reword) => rebase
+                .commit(
+                    None,
+                    &signature,
+                    step.and_then(|step| step.message.as_deref()),
+                )
+                .map(|_| ()),
+            _ => rebase.commit(None, &signature, None).map(|_| ()),
+        };
+        match result {
+            Err(err) if err.code() == git2::ErrorCode::Applied => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    pub fn rebase_status(&self) -> Result<Option<RebaseStatus>> {
+        let mut rebase = match self.repo.open_rebase(Some(&mut Self::rebase_options())) {
+            Ok(rebase) => rebase,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let plan = self.rebase_plan()?;
+        let current = rebase.operation_current();
+        let operations = (0..rebase.len())
+            .filter_map(|i| {
+                let id = rebase.nth(i)?.id();
+                let action = plan
+                    .as_ref()
+                    .and_then(|plan| plan.get(i))
+                    .map_or(RebaseAction::Pick, |step| step.action);
+                Some(format!("{} {}", action, id))
+            })
+            .collect_vec();
+        let onto = std::fs::read_to_string(self.repo.path().join(REBASE_DIR).join("onto"))
+            .unwrap_or_default();
+        Ok(Some(RebaseStatus {
+            interactive: plan.is_some(),
+            head_name: rebase.orig_head_name().unwrap_or("").to_string(),
+            onto: onto.trim().to_string(),
+            current: current.and_then(|i| operations.get(i).cloned()),
+            remaining: operations
+                .into_iter()
+                .skip(current.map_or(0, |i| i + 1))
+                .collect(),
+            conflicts: self.list_conflicts()?,
+        }))
+    }
+
+    pub fn rebase_continue(&self) -> Result<MergeOutcome> {
+        if self.repo.index()?.has_conflicts() {
+            return Err(anyhow::anyhow!(
+                "Resolve all conflicts before continuing the rebase"
+            ));
+        }
+        let mut rebase = self.repo.open_rebase(Some(&mut Self::rebase_options()))?;
+        let plan = self.rebase_plan()?;
+        self.commit_operation(&mut rebase, plan.as_deref())?;
+        self.run_rebase(&mut rebase, plan.as_deref())
+    }
+
+    pub fn rebase_skip(&self) -> Result<MergeOutcome> {
+        // A hard reset would also clean up the rebase state, so discard the changes by hand.
+        let mut index = self.repo.index()?;
+        index.read_tree(&self.repo.head()?.peel_to_tree()?)?;
+        index.write()?;
+        self.repo
+            .checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+        let mut rebase = self.repo.open_rebase(Some(&mut Self::rebase_options()))?;
+        let plan = self.rebase_plan()?;
+        self.run_rebase(&mut rebase, plan.as_deref())
+    }
+
+    pub fn rebase_abort(&self) -> Result<()> {
+        Ok(self
+            .repo
+            .open_rebase(Some(&mut Self::rebase_options()))?
+            .abort()?)
+    }
+
+    const UPLOADED_PATCH: &'static str = "htmx-git-apply";
+
+    /// Keeps an uploaded patch in the git directory until it is applied or discarded.
+    pub fn store_patch(&self, content: &[u8]) -> Result<()> {
+        std::fs::write(self.repo.path().join(Self::UPLOADED_PATCH), content)?;
+        Ok(())
+    }
+
+    pub fn discard_patch(&self) -> Result<()> {
+        let path = self.repo.path().join(Self::UPLOADED_PATCH);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn stored_patches(&self) -> Result<Vec<PatchEmail>> {
+        let content = std::fs::read(self.repo.path().join(Self::UPLOADED_PATCH))
+            .map_err(|_| anyhow::anyhow!("There is no uploaded patch"))?;
+        let patches = PatchEmail::split_series(&content)
+            .into_iter()
+            .filter_map(|message| PatchEmail::parse(message).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        if patches.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The uploaded file does not contain a patch"
+            ));
+        }
+        Ok(patches)
+    }
+
+    /// Parses the uploaded patches and checks each hunk against HEAD, applying the series in
+    /// memory so later patches are checked against the earlier ones.
+    pub fn preview_patches(&self) -> Result<Vec<PatchEmail>> {
+        let mut patches = self.stored_patches()?;
+        let mut tree = self.repo.head()?.peel_to_tree()?;
+        for patch in patches.iter_mut() {
+            let diff = Diff::from_buffer(&patch.diff)?;
+            let applies = self.check_hunks(&diff, |options| {
+                self.repo.apply_to_tree(&tree, &diff, Some(options)).is_ok()
+            });
+            patch.set_applies(&applies);
+            let mut options = git2::ApplyOptions::new();
+            Self::skip_hunks(&mut options, &applies);
+            match self.repo.apply_to_tree(&tree, &diff, Some(&mut options)) {
+                Ok(mut index) => tree = self.repo.find_tree(index.write_tree_to(&self.repo)?)?,
+                Err(err) => patch.error = Some(err.message().to_string()),
+            }
+        }
+        Ok(patches)
+    }
+
+    /// Tries every hunk on its own, returning whether each one applies.
+    fn check_hunks(
+        &self,
+        diff: &Diff,
+        mut check: impl FnMut(&mut git2::ApplyOptions) -> bool,
+    ) -> Vec<bool> {
+        let mut hunk_count = 0;
+        for idx in 0..diff.deltas().len() {
+            if let Ok(Some(patch)) = git2::Patch::from_diff(diff, idx) {
+                hunk_count += patch.num_hunks();
+            }
+        }
+        (0..hunk_count)
+            .map(|hunk| {
+                let mut seen = 0;
+                let mut options = git2::ApplyOptions::new();
+                options.hunk_callback(|_| {
+                    seen += 1;
+                    seen - 1 == hunk
+                });
+                check(&mut options)
+            })
+            .collect()
+    }
+
+    /// Leaves out the hunks that do not apply. git2 keeps a pointer to the options for its
+    /// callbacks, so they must not be moved after this.
+    fn skip_hunks<'a>(options: &mut git2::ApplyOptions<'a>, applies: &'a [bool]) {
+        let mut seen = 0;
+        options.hunk_callback(move |_| {
+            seen += 1;
+            applies.get(seen - 1).copied().unwrap_or(true)
+        });
+    }
+
+    /// Applies the uploaded patches in order, stopping at the first one with hunks that do not
+    /// apply unless `skip_failed` is set, in which case those hunks are left out. When the series
+    /// stops, the patches that were not applied stay uploaded so they can be retried.
+    pub fn apply_patches(&self, target: ApplyTarget, skip_failed: bool) -> Result<Vec<PatchEmail>> {
+        let mut patches = self.stored_patches()?;
+        let mut index = self.repo.index()?;
+        index.read(false)?;
+        if target == ApplyTarget::Commit {
+            let head_tree = self.repo.head()?.peel_to_tree()?;
+            if index.write_tree()? != head_tree.id() {
+                return Err(anyhow::anyhow!(
+                    "Commit or unstage the staged changes before applying patches as commits"
+                ));
+            }
+        }
+        let mut stopped = None;
+        for (i, patch) in patches.iter_mut().enumerate() {
+            let diff = Diff::from_buffer(&patch.diff)?;
+            let applies = self.check_hunks(&diff, |options| {
+                options.check(true);
+                self.repo.apply(&diff, target.into(), Some(options)).is_ok()
+            });
+            patch.set_applies(&applies);
+            if patch.failed_hunks() > 0 && !skip_failed {
+                patch.error = Some(format!("{} hunk(s) did not apply", patch.failed_hunks()));
+                stopped = Some(i);
+                break;
+            }
+            let mut options = git2::ApplyOptions::new();
+            Self::skip_hunks(&mut options, &applies);
+            if let Err(err) = self.repo.apply(&diff, target.into(), Some(&mut options)) {
+                patch.error = Some(err.message().to_string());
+                stopped = Some(i);
+                break;
+            }
+            if target == ApplyTarget::Commit {
+                let tree = self.repo.find_tree(self.repo.index()?.write_tree()?)?;
+                let head = self.repo.head()?.peel_to_commit()?;
+                let committer = self.repo.signature()?;
+                let author = patch.signature().unwrap_or(committer.clone());
+                let id = self.repo.commit(
+                    Some("HEAD"),
+                    &author,
+                    &committer,
+                    &patch.commit_message(),
+                    &tree,
+                    &[&head],
+                )?;
+                patch.commit = Some(id.to_string());
+            }
+        }
+        match stopped {
+            Some(i) => {
+                let rest = patches[i..]
+                    .iter()
+                    .map(|p| p.source.as_slice())
+                    .collect_vec();
+                self.store_patch(&rest.concat())?
+            }
+            None => self.discard_patch()?,
+        }
+        Ok(patches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_conflict_markers_splits_clean_text_and_conflicts() {
+        let (_, segments) = merge_conflicted("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+        assert_eq!(
+            segments,
+            vec![
+                ConflictSegment::Resolved("a\n".to_string()),
+                ConflictSegment::Conflict {
+                    index: 0,
+                    ours: "ours\n".to_string(),
+                    ancestor: "b\n".to_string(),
+                    theirs: "theirs\n".to_string(),
+                },
+                ConflictSegment::Resolved("c\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_conflict_markers_without_conflicts() {
+        assert_eq!(
+            parse_conflict_markers("a\nb\n", 7),
+            vec![ConflictSegment::Resolved("a\nb\n".to_string())]
+        );
+        assert_eq!(parse_conflict_markers("", 7), vec![]);
+    }
+
+    #[test]
+    fn parse_conflict_markers_keeps_marker_lookalikes_in_content() {
+        let (merged, segments) =
+            merge_conflicted("base\n", "=======\nours\n", ">>>>>>> theirs\ntheirs\n");
+        assert!(merged.starts_with("<<<<<<<< ours\n"));
+        assert_eq!(
+            segments,
+            vec![ConflictSegment::Conflict {
+                index: 0,
+                ours: "=======\nours\n".to_string(),
+                ancestor: "base\n".to_string(),
+                theirs: ">>>>>>> theirs\ntheirs\n".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_conflict_markers_numbers_conflicts() {
+        let merged = "<<<<<<< ours\na\n=======\nb\n>>>>>>> theirs\nx\n<<<<<<< ours\nc\n||||||| original\nd\n=======\ne\n>>>>>>> theirs\n";
+        let segments = parse_conflict_markers(merged, 7);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(
+            segments[2],
+            ConflictSegment::Conflict {
+                index: 1,
+                ours: "c\n".to_string(),
+                ancestor: "d\n".to_string(),
+                theirs: "e\n".to_string(),
+            }
+        );
+    }
+
+    fn diff_line(operation: DiffLineType, content: &str) -> DiffLineData {
+        DiffLineData {
+            content: content.to_string(),
+            file_path: None,
+            operation,
+            old_line_number: None,
+            new_line_number: None,
+            segments: vec![],
+        }
+    }
+
+    fn hunk(lines: Vec<DiffLineData>) -> DiffHunkItem {
+        DiffHunkItem {
+            hunk_diff: diff_line(DiffLineType::HunkHeader, "@@ -1 +1 @@"),
+            lines,
+            old_start: 1,
+            old_lines: 1,
+            new_start: 1,
+            new_lines: 1,
+            context_before: None,
+        }
+    }
+
+    fn row_contents(hunk: &DiffHunkItem) -> Vec<(Option<&str>, Option<&str>)> {
+        hunk.split_rows()
+            .into_iter()
+            .map(|row| {
+                (
+                    row.left.map(|line| line.content.as_str()),
+                    row.right.map(|line| line.content.as_str()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_rows_pairs_deletions_with_following_additions() {
+        let hunk = hunk(vec![
+            diff_line(DiffLineType::Context, "a"),
+            diff_line(DiffLineType::Deletion, "b"),
+            diff_line(DiffLineType::Deletion, "c"),
+            diff_line(DiffLineType::Addition, "B"),
+            diff_line(DiffLineType::Context, "d"),
+        ]);
+        assert_eq!(
+            row_contents(&hunk),
+            vec![
+                (Some("a"), Some("a")),
+                (Some("b"), Some("B")),
+                (Some("c"), None),
+                (Some("d"), Some("d")),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_rows_keeps_unpaired_additions_on_the_right() {
+        let hunk = hunk(vec![
+            diff_line(DiffLineType::Addition, "x"),
+            diff_line(DiffLineType::Addition, "y"),
+            diff_line(DiffLineType::Deletion, "z"),
+        ]);
+        assert_eq!(
+            row_contents(&hunk),
+            vec![(None, Some("x")), (None, Some("y")), (Some("z"), None)]
+        );
+    }
+
+    #[test]
+    fn split_rows_starts_a_new_run_after_additions() {
+        let hunk = hunk(vec![
+            diff_line(DiffLineType::Deletion, "a"),
+            diff_line(DiffLineType::Addition, "A"),
+            diff_line(DiffLineType::Deletion, "b"),
+            diff_line(DiffLineType::Addition, "B"),
+        ]);
+        assert_eq!(
+            row_contents(&hunk),
+            vec![(Some("a"), Some("A")), (Some("b"), Some("B"))]
+        );
+    }
+
+    #[test]
+    fn diff_anchor_keeps_similar_paths_apart() {
+        let anchors = ["a/b.rs", "a-b.rs", "a_b.rs", "a b.rs", "a\u{e9}.rs"]
+            .map(diff_anchor)
+            .to_vec();
+        assert_eq!(anchors[0], "diff-a-2fb-2ers");
+        assert_eq!(anchors[1], "diff-a-2db-2ers");
+        assert_eq!(anchors.iter().unique().count(), anchors.len());
+        assert!(anchors.iter().all(|anchor| anchor
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')));
+    }
+
+    fn segment_texts(segments: &[DiffSegment]) -> Vec<(&str, bool)> {
+        segments
+            .iter()
+            .map(|segment| (segment.text.as_str(), segment.changed))
+            .collect()
+    }
+
+    #[test]
+    fn word_diff_marks_changed_words() {
+        let (old, new) = word_diff("let x = foo(1);", "let x = bar(1);").unwrap();
+        assert_eq!(
+            segment_texts(&old),
+            vec![("let x = ", false), ("foo", true), ("(1);", false)]
+        );
+        assert_eq!(
+            segment_texts(&new),
+            vec![("let x = ", false), ("bar", true), ("(1);", false)]
+        );
+    }
+
+    #[test]
+    fn word_diff_handles_insertions() {
+        let (old, new) = word_diff("a b", "a new b").unwrap();
+        assert_eq!(segment_texts(&old), vec![("a b", false)]);
+        assert_eq!(
+            segment_texts(&new),
+            vec![("a ", false), ("new ", true), ("b", false)]
+        );
+    }
+
+    #[test]
+    fn word_diff_gives_up_on_unrelated_lines() {
+        assert!(word_diff("completely different", "nothing alike here").is_none());
+    }
+
+    #[test]
+    fn word_diff_gives_up_on_long_lines() {
+        let line = "a ".repeat(400);
+        assert!(word_diff(&line, &line).is_none());
+    }
+
+    #[test]
+    fn word_diff_keeps_multibyte_characters_whole() {
+        let (old, new) = word_diff("héllo wörld", "héllo wörlds").unwrap();
+        assert_eq!(
+            segment_texts(&old),
+            vec![("héllo ", false), ("wörld", true)]
+        );
+        assert_eq!(
+            segment_texts(&new),
+            vec![("héllo ", false), ("wörlds", true)]
+        );
+    }
+
+    fn gap(start: u32, end: Option<u32>) -> ContextGap {
+        ContextGap {
+            start,
+            end,
+            old_offset: 0,
+        }
+    }
+
+    fn bounds(gap: &Option<ContextGap>) -> Option<(u32, Option<u32>)> {
+        gap.as_ref().map(|gap| (gap.start, gap.end))
+    }
+
+    #[test]
+    fn expand_all_reveals_the_whole_gap() {
+        let (range, remaining) = gap(5, Some(10)).expand(ExpandDirection::All, 3, 100);
+        assert_eq!(range, 5..=10);
+        assert!(remaining.is_none());
+    }
+
+    #[test]
+    fn expand_up_keeps_the_gap_above() {
+        let (range, remaining) = gap(5, Some(20)).expand(ExpandDirection::Up, 10, 100);
+        assert_eq!(range, 11..=20);
+        assert_eq!(bounds(&remaining), Some((5, Some(10))));
+    }
+
+    #[test]
+    fn expand_down_keeps_the_gap_below() {
+        let (range, remaining) = gap(5, Some(20)).expand(ExpandDirection::Down, 10, 100);
+        assert_eq!(range, 5..=14);
+        assert_eq!(bounds(&remaining), Some((15, Some(20))));
+    }
+
+    #[test]
+    fn expand_trailing_gap_stops_at_the_end_of_the_file() {
+        let (range, remaining) = gap(90, None).expand(ExpandDirection::Down, 5, 100);
+        assert_eq!(range, 90..=94);
+        assert_eq!(bounds(&remaining), Some((95, None)));
+        let (range, remaining) = gap(90, None).expand(ExpandDirection::Down, 50, 100);
+        assert_eq!(range, 90..=100);
+        assert!(remaining.is_none());
+    }
+
+    #[test]
+    fn expand_treats_a_zero_step_as_one_line() {
+        let (range, remaining) = gap(5, Some(20)).expand(ExpandDirection::Up, 0, 100);
+        assert_eq!(range, 20..=20);
+        assert_eq!(bounds(&remaining), Some((5, Some(19))));
+        let (range, _) = gap(5, Some(20)).expand(ExpandDirection::Down, 0, 100);
+        assert_eq!(range, 5..=5);
+    }
+
+    #[test]
+    fn expand_does_not_overflow_on_huge_steps() {
+        let (range, remaining) = gap(5, Some(20)).expand(ExpandDirection::Down, u32::MAX, 100);
+        assert_eq!(range, 5..=20);
+        assert!(remaining.is_none());
+        let (range, remaining) = gap(5, Some(20)).expand(ExpandDirection::Up, u32::MAX, 100);
+        assert_eq!(range, 5..=20);
+        assert!(remaining.is_none());
+        let (range, _) = gap(u32::MAX, None).expand(ExpandDirection::Down, u32::MAX, u32::MAX);
+        assert_eq!(range, u32::MAX..=u32::MAX);
+    }
+
+    #[test]
+    fn parse_gitmodules_reads_each_section() {
+        let urls = parse_gitmodules(
+            "[submodule \"libs/a\"]\n\tpath = libs/a\n\turl = https://example.com/a.git\n\
+             [submodule \"b\"]\n\turl=\"git@example.com:b.git\"\n\tpath=\"vendor/b\"\n",
+        );
+        assert_eq!(urls.len(), 2);
+        assert_eq!(urls["libs/a"], "https://example.com/a.git");
+        assert_eq!(urls["vendor/b"], "git@example.com:b.git");
+    }
+
+    #[test]
+    fn parse_gitmodules_skips_incomplete_sections() {
+        let urls = parse_gitmodules(
+            "[submodule \"a\"]\n\turl = https://example.com/a.git\n\
+             [submodule \"b\"]\n\tpath = b\n\
+             # comment\n\
+             [submodule \"c\"]\n\tpath = c\n\turl = https://example.com/c.git\n",
+        );
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls["c"], "https://example.com/c.git");
+        assert!(parse_gitmodules("").is_empty());
+    }
+
+    #[test]
+    fn resolve_symlink_is_relative_to_the_link_directory() {
+        assert_eq!(
+            resolve_symlink("", "README.md").as_deref(),
+            Some("README.md")
+        );
+        assert_eq!(
+            resolve_symlink("docs/guide", "../api/./index.md").as_deref(),
+            Some("docs/api/index.md")
+        );
+        assert_eq!(
+            resolve_symlink("docs/", "a//b").as_deref(),
+            Some("docs/a/b")
+        );
+        assert_eq!(resolve_symlink("docs", "..").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn resolve_symlink_rejects_targets_outside_the_repository() {
+        assert_eq!(resolve_symlink("docs", "/etc/passwd"), None);
+        assert_eq!(resolve_symlink("", "../outside"), None);
+        assert_eq!(resolve_symlink("docs", "../../outside"), None);
+    }
+
+    fn cache_key(n: usize) -> (Oid, Oid) {
+        let mut bytes = [0; 20];
+        bytes[..8].copy_from_slice(&n.to_be_bytes());
+        (Oid::zero(), Oid::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn last_commit_cache_evicts_the_least_recently_used_directory() {
+        let mut cache = LastCommitCache::default();
+        for n in 0..LAST_COMMIT_CACHE_SIZE {
+            cache.insert(cache_key(n), HashMap::new());
+        }
+        assert!(cache.get(cache_key(0)).is_some());
+        cache.insert(cache_key(LAST_COMMIT_CACHE_SIZE), HashMap::new());
+        assert_eq!(cache.entries.len(), LAST_COMMIT_CACHE_SIZE);
+        assert!(cache.get(cache_key(0)).is_some());
+        assert!(cache.get(cache_key(1)).is_none());
+        assert!(cache.get(cache_key(LAST_COMMIT_CACHE_SIZE)).is_some());
+    }
+
+    const FORMAT_PATCH: &[u8] =
+        b"From 1234567890abcdef1234567890abcdef12345678 Mon Sep 17 00:00:00 2001
+From: Jane Doe <jane@example.com>
+Date: Tue, 2 Jan 2024 10:00:00 +0100
+Subject: [PATCH 1/2] Change the
+ greeting
+
+Say hello to everyone.
+---
+ greeting.txt | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+diff --git a/greeting.txt b/greeting.txt
+index 1111111..2222222 100644
+--- a/greeting.txt
++++ b/greeting.txt
+@@ -1,2 +1,2 @@
+-hello
++hello everyone
+ bye
+-- 
+2.43.0
+
+";
+
+    #[test]
+    fn parse_reads_format_patch_emails() {
+        let patch = PatchEmail::parse(FORMAT_PATCH).unwrap().unwrap();
+        assert_eq!(patch.author.as_deref(), Some("Jane Doe <jane@example.com>"));
+        assert_eq!(patch.subject.as_deref(), Some("Change the greeting"));
+        assert_eq!(patch.body, "Say hello to everyone.");
+        assert_eq!(patch.files.len(), 1);
+        assert_eq!(patch.files[0].hunks[0].lines.len(), 3);
+        assert!(patch.diff.ends_with(b" bye\n"));
+    }
+
+    #[test]
+    fn parse_reads_plain_diffs() {
+        let patch = PatchEmail::parse(
+            b"diff --git a/a.txt b/a.txt\n--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+b\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(patch.author.is_none());
+        assert!(patch.subject.is_none());
+        assert_eq!(patch.files.len(), 1);
+    }
+
+    #[test]
+    fn parse_ignores_messages_without_a_diff() {
+        assert!(PatchEmail::parse(b"Subject: hello\n\nNo patch here.\n")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn parse_keeps_removed_lines_that_look_like_a_signature() {
+        let message = b"Subject: [PATCH] Remove dashes
+
+---
+diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,2 @@
+ a
+-- 
+-b
++c
+-- 
+2.43.0
+";
+        let patch = PatchEmail::parse(message).unwrap().unwrap();
+        let lines = &patch.files[0].hunks[0].lines;
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].operation, DiffLineType::Deletion);
+        assert_eq!(lines[1].content, "-");
+        assert!(patch.diff.ends_with(b"+c\n"));
+    }
+
+    #[test]
+    fn parse_keeps_crlf_and_non_utf8_bytes_in_the_diff() {
+        let message = b"Subject: Latin-1\r\n\r\ndiff --git a/a.txt b/a.txt\r\n--- a/a.txt\r\n+++ b/a.txt\r\n@@ -1 +1 @@\r\n-caf\xe9\r\n+th\xe9\r\n";
+        let patch = PatchEmail::parse(message).unwrap().unwrap();
+        assert_eq!(patch.subject.as_deref(), Some("Latin-1"));
+        assert!(patch.diff.ends_with(b"-caf\xe9\r\n+th\xe9\r\n"));
+        assert_eq!(patch.files[0].hunks[0].lines.len(), 2);
+    }
+
+    #[test]
+    fn parse_decodes_encoded_headers() {
+        let message = b"From: =?ISO-8859-1?Q?Andr=E9_M=FCller?= <andre@example.com>
+Subject: [PATCH] =?UTF-8?B?Q2Fmw6k=?=
+ =?UTF-8?Q?_au_lait?=
+
+diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1 @@
+-a
++b
+";
+        let patch = PatchEmail::parse(message).unwrap().unwrap();
+        assert_eq!(
+            patch.author.as_deref(),
+            Some("Andr\u{e9} M\u{fc}ller <andre@example.com>")
+        );
+        assert_eq!(patch.subject.as_deref(), Some("Caf\u{e9} au lait"));
+    }
+
+    #[test]
+    fn decode_header_leaves_invalid_encoded_words_alone() {
+        assert_eq!(
+            decode_header("=?bogus?Q?x?= and =? more"),
+            "=?bogus?Q?x?= and =? more"
+        );
+        assert_eq!(decode_header("plain text"), "plain text");
+    }
+
+    /// A fresh repository in the temporary directory, removed when the guard is dropped.
+    struct TestRepo {
+        path: std::path::PathBuf,
+        git: GitWrapper,
+    }
+
+    impl TestRepo {
+        fn new(name: &str) -> TestRepo {
+            let path = std::env::temp_dir().join(format!(
+                "htmx-git-client-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            let mut config = Repository::init(&path).unwrap().config().unwrap();
+            config.set_str("user.name", "t").unwrap();
+            config.set_str("user.email", "t@t").unwrap();
+            let git = GitWrapper::new(path.to_str().unwrap()).unwrap();
+            TestRepo { path, git }
+        }
+
+        /// Writes the files to the working tree and commits them on top of HEAD.
+        fn commit(&self, files: &[(&str, &str)], message: &str) -> Oid {
+            let repo = self.git.inner();
+            let mut index = repo.index().unwrap();
+            for (path, content) in files {
+                std::fs::write(self.path.join(path), content).unwrap();
+                index.add_path(std::path::Path::new(path)).unwrap();
+            }
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = repo.signature().unwrap();
+            let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+            let parents: Vec<&git2::Commit> = parent.iter().collect();
+            repo.commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parents,
+            )
+            .unwrap()
+        }
+
+        /// Creates a branch at HEAD.
+        fn branch(&self, name: &str) {
+            let head = self.git.inner().head().unwrap().peel_to_commit().unwrap();
+            self.git.inner().branch(name, &head, false).unwrap();
+        }
+
+        /// Switches the working tree to a branch, discarding local changes.
+        fn checkout(&self, name: &str) {
+            let repo = self.git.inner();
+            repo.set_head(&format!("refs/heads/{}", name)).unwrap();
+            repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+                .unwrap();
+        }
+
+        fn read(&self, path: &str) -> String {
+            std::fs::read_to_string(self.path.join(path)).unwrap()
+        }
+
+        fn head(&self) -> git2::Commit<'_> {
+            self.git.inner().head().unwrap().peel_to_commit().unwrap()
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// A repository on `main` where `feature` and `main` each added a commit after `base`.
+    fn diverged_repo(name: &str, feature: &[(&str, &str)], main: &[(&str, &str)]) -> TestRepo {
+        let repo = TestRepo::new(name);
+        repo.commit(&[("a.txt", "one\n")], "base");
+        repo.branch("main");
+        repo.branch("feature");
+        repo.checkout("feature");
+        repo.commit(feature, "feature change");
+        repo.checkout("main");
+        repo.commit(main, "main change");
+        repo
+    }
+
+    fn error_code(err: anyhow::Error) -> Option<ErrorCode> {
+        err.downcast_ref::<git2::Error>().map(|err| err.code())
+    }
+
+    #[test]
+    fn merge_fast_forwards_only_when_possible() {
+        let repo = diverged_repo("merge-ff-only", &[("b.txt", "b\n")], &[("c.txt", "c\n")]);
+        let err = repo
+            .git
+            .merge("feature", MergeMode::FastForwardOnly)
+            .err()
+            .unwrap();
+        assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+
+        repo.checkout("feature");
+        repo.branch("behind");
+        repo.commit(&[("d.txt", "d\n")], "ahead");
+        repo.checkout("behind");
+        let preview = repo.git.merge_preview("feature").unwrap();
+        assert!(preview.fast_forward);
+        assert_eq!(preview.commits.len(), 1);
+        let outcome = repo
+            .git
+            .merge("feature", MergeMode::FastForwardOnly)
+            .unwrap();
+        assert!(
+            matches!(outcome, MergeOutcome::FastForwarded(id) if id == repo.head().id().to_string())
+        );
+        assert_eq!(repo.read("d.txt"), "d\n");
+    }
+
+    #[test]
+    fn merge_records_both_parents() {
+        let repo = diverged_repo("merge-commit", &[("b.txt", "b\n")], &[("c.txt", "c\n")]);
+        let preview = repo.git.merge_preview("feature").unwrap();
+        assert!(!preview.fast_forward && !preview.unrelated);
+        assert!(preview.conflicts.is_empty());
+        let outcome = repo.git.merge("feature", MergeMode::FastForward).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        let head = repo.head();
+        assert_eq!(head.parent_count(), 2);
+        assert_eq!(head.summary(), Some("Merge feature into main"));
+        assert_eq!(repo.read("b.txt"), "b\n");
+        assert!(repo.git.merge_status().unwrap().clean);
+    }
+
+    #[test]
+    fn merge_squash_lists_every_commit() {
+        let repo = diverged_repo("merge-squash", &[("b.txt", "b\n")], &[("c.txt", "c\n")]);
+        repo.checkout("feature");
+        repo.commit(
+            &[("b.txt", "b2\n")],
+            "second feature change\n\nWith a body.",
+        );
+        repo.checkout("main");
+        let outcome = repo.git.merge("feature", MergeMode::Squash).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        let head = repo.head();
+        assert_eq!(head.parent_count(), 1);
+        let message = head.message().unwrap();
+        assert!(message.starts_with("Squashed commit of the following:\n"));
+        assert!(message.contains("    feature change\n"));
+        assert!(message.contains("    second feature change\n\n    With a body.\n"));
+        assert_eq!(message.matches("\ncommit ").count(), 2);
+        assert_eq!(repo.read("b.txt"), "b2\n");
+        assert!(repo.git.merge_status().unwrap().clean);
+    }
+
+    #[test]
+    fn merge_stops_on_conflicts() {
+        let repo = diverged_repo(
+            "merge-conflict",
+            &[("a.txt", "two\n")],
+            &[("a.txt", "three\n")],
+        );
+        let preview = repo.git.merge_preview("feature").unwrap();
+        assert_eq!(preview.conflicts, vec!["a.txt"]);
+        let outcome = repo.git.merge("feature", MergeMode::NoFastForward).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicted(paths) if paths == vec!["a.txt"]));
+        let status = repo.git.merge_status().unwrap();
+        assert!(status.in_progress());
+        assert_eq!(status.state, "Merge");
+        repo.git.abort_merge().unwrap();
+        assert!(repo.git.merge_status().unwrap().clean);
+        assert_eq!(repo.read("a.txt"), "three\n");
+    }
+
+    #[test]
+    fn merge_rejects_unrelated_histories() {
+        let repo = TestRepo::new("merge-unrelated");
+        repo.commit(&[("a.txt", "one\n")], "base");
+        let git = repo.git.inner();
+        let signature = git.signature().unwrap();
+        let tree = git
+            .find_tree(git.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        git.commit(
+            Some("refs/heads/other"),
+            &signature,
+            &signature,
+            "root",
+            &tree,
+            &[],
+        )
+        .unwrap();
+        assert!(repo.git.merge_preview("other").unwrap().unrelated);
+        let err = repo
+            .git
+            .merge("other", MergeMode::FastForward)
+            .err()
+            .unwrap();
+        assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+    }
+
+    #[test]
+    fn cherry_pick_records_the_origin() {
+        let repo = diverged_repo("cherry-pick", &[("b.txt", "b\n")], &[("c.txt", "c\n")]);
+        let picked = repo.git.resolve_commit("feature").unwrap();
+        let outcome = repo.git.cherry_pick(&picked[..7], None, true).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        assert_eq!(
+            repo.head().message(),
+            Some(format!("feature change\n\n(cherry picked from commit {})", picked).as_str())
+        );
+        assert_eq!(repo.read("b.txt"), "b\n");
+
+        let err = repo.git.cherry_pick("feature", None, false).err().unwrap();
+        assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+        assert!(repo.git.merge_status().unwrap().clean);
+    }
+
+    #[test]
+    fn cherry_pick_saves_the_message_on_conflicts() {
+        let repo = diverged_repo(
+            "cherry-pick-conflict",
+            &[("a.txt", "two\n")],
+            &[("a.txt", "three\n")],
+        );
+        let outcome = repo.git.cherry_pick("feature", None, false).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicted(paths) if paths == vec!["a.txt"]));
+        let status = repo.git.merge_status().unwrap();
+        assert_eq!(status.state, "CherryPick");
+        assert_eq!(status.message.as_deref(), Some("feature change"));
+    }
+
+    #[test]
+    fn cherry_pick_and_revert_check_the_mainline() {
+        let repo = diverged_repo("pick-mainline", &[("b.txt", "b\n")], &[("c.txt", "c\n")]);
+        repo.git.merge("feature", MergeMode::NoFastForward).unwrap();
+        let merge = repo.head().id().to_string();
+        for mainline in [0, 3] {
+            let err = repo.git.revert(&merge, Some(mainline)).err().unwrap();
+            assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+            let err = repo
+                .git
+                .cherry_pick(&merge, Some(mainline), false)
+                .err()
+                .unwrap();
+            assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+        }
+        let outcome = repo.git.revert(&merge, Some(1)).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        let head = repo.head();
+        assert!(head.message().unwrap().contains(&format!(
+            "This reverts commit {}, reversing\nchanges made to {}.",
+            merge,
+            head.parent(0).unwrap().parent_id(0).unwrap()
+        )));
+        assert!(!repo.path.join("b.txt").exists());
+    }
+
+    /// A repository on `main` with three commits that each rewrite `a.txt`.
+    fn history_repo(name: &str) -> TestRepo {
+        let repo = TestRepo::new(name);
+        for n in ["one", "two", "three"] {
+            repo.commit(&[("a.txt", &format!("{}\n", n))], n);
+        }
+        repo.branch("main");
+        repo.checkout("main");
+        repo
+    }
+
+    fn index_content(repo: &TestRepo, path: &str) -> String {
+        let index = repo.git.inner().index().unwrap();
+        let entry = index.get_path(std::path::Path::new(path), 0).unwrap();
+        let blob = repo.git.inner().find_blob(entry.id).unwrap();
+        String::from_utf8(blob.content().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn reset_moves_head_index_and_working_tree_by_mode() {
+        let repo = history_repo("reset-modes");
+        let first = repo.git.resolve_commit("HEAD~2").unwrap();
+        assert_eq!(repo.git.unreachable_after_reset("HEAD~2").unwrap().len(), 2);
+
+        repo.git.reset(&first[..7], ResetMode::Soft).unwrap();
+        assert_eq!(repo.head().id().to_string(), first);
+        assert_eq!(index_content(&repo, "a.txt"), "three\n");
+        assert_eq!(repo.read("a.txt"), "three\n");
+
+        repo.git.undo_reset(ResetMode::Hard).unwrap();
+        repo.git.reset("HEAD~1", ResetMode::Mixed).unwrap();
+        assert_eq!(index_content(&repo, "a.txt"), "two\n");
+        assert_eq!(repo.read("a.txt"), "three\n");
+
+        repo.git.undo_reset(ResetMode::Hard).unwrap();
+        repo.git.reset("main~1", ResetMode::Hard).unwrap();
+        assert_eq!(index_content(&repo, "a.txt"), "two\n");
+        assert_eq!(repo.read("a.txt"), "two\n");
+    }
+
+    #[test]
+    fn undo_reset_only_undoes_once() {
+        let repo = history_repo("reset-undo");
+        let tip = repo.head().id().to_string();
+        let err = repo.git.undo_reset(ResetMode::Hard).err().unwrap();
+        assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+
+        repo.git.reset("HEAD~2", ResetMode::Hard).unwrap();
+        assert_eq!(repo.git.undo_reset(ResetMode::Hard).unwrap(), tip);
+        assert_eq!(repo.head().id().to_string(), tip);
+        assert_eq!(repo.read("a.txt"), "three\n");
+        let entries = repo.git.reflog("main").unwrap();
+        assert_eq!(
+            entries[0].message.as_deref(),
+            Some(format!("undo reset: moving to {}", tip).as_str())
+        );
+        assert_eq!(entries[0].old_id, entries[1].new_id.clone().into());
+
+        let err = repo.git.undo_reset(ResetMode::Hard).err().unwrap();
+        assert_eq!(error_code(err), Some(ErrorCode::Invalid));
+        assert_eq!(repo.head().id().to_string(), tip);
+    }
+
+    #[test]
+    fn create_branch_restores_a_commit_from_the_reflog() {
+        let repo = history_repo("reflog-branch");
+        let lost = repo.head().id().to_string();
+        repo.git.reset("HEAD~2", ResetMode::Hard).unwrap();
+        let entries = repo.git.reflog("HEAD").unwrap();
+        assert!(entries[0]
+            .message
+            .as_deref()
+            .unwrap()
+            .starts_with("reset: moving to"));
+        let old_id = entries[0].old_id.clone().unwrap();
+        assert_eq!(old_id, lost);
+
+        repo.git.create_branch("rescued", &old_id[..7]).unwrap();
+        assert_eq!(repo.git.resolve_commit("rescued").unwrap(), lost);
+        let err = repo.git.create_branch("rescued", "HEAD").err().unwrap();
+        assert_eq!(err.code(), ErrorCode::Exists);
+        assert!(repo.git.reflog("missing").is_err());
+    }
+
+    /// A repository on `feature`, which added one commit per file on top of `base`, while `main`
+    /// added `main.txt`.
+    fn rebase_repo(name: &str, files: &[(&str, &str)]) -> (TestRepo, Vec<String>) {
+        let repo = TestRepo::new(name);
+        repo.commit(&[("a.txt", "one\n")], "base");
+        repo.branch("main");
+        repo.branch("feature");
+        repo.checkout("main");
+        repo.commit(
+            &[("main.txt", "main\n"), ("a.txt", "main\n")],
+            "main change",
+        );
+        repo.checkout("feature");
+        let ids = files
+            .iter()
+            .map(|(path, content)| {
+                let message = format!("change {}", path);
+                repo.commit(&[(path, content)], &message).to_string()
+            })
+            .collect();
+        (repo, ids)
+    }
+
+    fn step(action: RebaseAction, id: &str, message: Option<&str>) -> RebaseStep {
+        RebaseStep {
+            action,
+            id: id.to_string(),
+            message: message.map(|m| m.to_string()),
+        }
+    }
+
+    fn resolve(repo: &TestRepo, path: &str, content: &str) {
+        std::fs::write(repo.path.join(path), content).unwrap();
+        let mut index = repo.git.inner().index().unwrap();
+        index.add_path(std::path::Path::new(path)).unwrap();
+        index.write().unwrap();
+    }
+
+    #[test]
+    fn rebase_interactive_applies_every_action() {
+        let files = [
+            ("1.txt", "1\n"),
+            ("2.txt", "2\n"),
+            ("3.txt", "3\n"),
+            ("4.txt", "4\n"),
+            ("5.txt", "5\n"),
+        ];
+        let (repo, ids) = rebase_repo("rebase-actions", &files);
+        let steps = vec![
+            step(RebaseAction::Pick, &ids[2], None),
+            step(RebaseAction::Reword, &ids[0], Some("reworded")),
+            step(RebaseAction::Squash, &ids[1], None),
+            step(RebaseAction::Drop, &ids[3], None),
+            step(RebaseAction::Fixup, &ids[4], None),
+        ];
+        let outcome = repo.git.rebase_interactive("main", steps).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        assert!(repo.git.rebase_status().unwrap().is_none());
+        assert_eq!(repo.git.get_current_branch().unwrap(), "feature");
+
+        let head = repo.head();
+        assert_eq!(head.message(), Some("reworded\n\nchange 2.txt"));
+        assert_eq!(head.author().name(), Some("t"));
+        let picked = head.parent(0).unwrap();
+        assert_eq!(picked.message(), Some("change 3.txt"));
+        assert_eq!(
+            picked.parent_id(0).unwrap().to_string(),
+            repo.git.resolve_commit("main").unwrap()
+        );
+        for (path, content) in [
+            ("1.txt", "1\n"),
+            ("2.txt", "2\n"),
+            ("3.txt", "3\n"),
+            ("5.txt", "5\n"),
+        ] {
+            assert_eq!(repo.read(path), content);
+        }
+        assert!(!repo.path.join("4.txt").exists());
+        assert_eq!(repo.read("main.txt"), "main\n");
+    }
+
+    #[test]
+    fn rebase_interactive_rejects_a_stale_plan() {
+        let (repo, ids) = rebase_repo("rebase-stale", &[("1.txt", "1\n"), ("2.txt", "2\n")]);
+        let tip = repo.head().id();
+        let steps = vec![step(RebaseAction::Pick, &ids[0], None)];
+        assert!(repo.git.rebase_interactive("main", steps).is_err());
+        let steps = vec![
+            step(RebaseAction::Squash, &ids[0], None),
+            step(RebaseAction::Pick, &ids[1], None),
+        ];
+        assert!(repo.git.rebase_interactive("main", steps).is_err());
+        assert!(repo.git.rebase_status().unwrap().is_none());
+        assert_eq!(repo.head().id(), tip);
+    }
+
+    #[test]
+    fn rebase_interactive_continues_after_conflicts() {
+        let (repo, ids) = rebase_repo("rebase-continue", &[("a.txt", "two\n"), ("2.txt", "2\n")]);
+        let steps = vec![
+            step(RebaseAction::Reword, &ids[0], Some("reworded")),
+            step(RebaseAction::Pick, &ids[1], None),
+        ];
+        let outcome = repo.git.rebase_interactive("main", steps).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicted(paths) if paths == vec!["a.txt"]));
+        let status = repo.git.rebase_status().unwrap().unwrap();
+        assert!(status.interactive);
+        assert_eq!(status.head_name, "refs/heads/feature");
+        assert_eq!(status.current, Some(format!("reword {}", ids[0])));
+        assert_eq!(status.remaining, vec![format!("pick {}", ids[1])]);
+        assert_eq!(status.conflicts, vec!["a.txt"]);
+        assert!(repo.git.rebase_continue().is_err());
+
+        resolve(&repo, "a.txt", "resolved\n");
+        let outcome = repo.git.rebase_continue().unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        assert!(repo.git.rebase_status().unwrap().is_none());
+        let head = repo.head();
+        assert_eq!(head.message(), Some("change 2.txt"));
+        assert_eq!(head.parent(0).unwrap().message(), Some("reworded"));
+        assert_eq!(repo.read("a.txt"), "resolved\n");
+        assert_eq!(repo.git.get_current_branch().unwrap(), "feature");
+    }
+
+    #[test]
+    fn rebase_skips_the_conflicting_commit() {
+        let (repo, _) = rebase_repo("rebase-skip", &[("a.txt", "two\n"), ("2.txt", "2\n")]);
+        let outcome = repo.git.rebase("main").unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicted(_)));
+        let status = repo.git.rebase_status().unwrap().unwrap();
+        assert!(!status.interactive);
+        let outcome = repo.git.rebase_skip().unwrap();
+        assert!(matches!(outcome, MergeOutcome::Committed(_)));
+        let head = repo.head();
+        assert_eq!(head.message(), Some("change 2.txt"));
+        assert_eq!(head.parent(0).unwrap().message(), Some("main change"));
+        assert_eq!(repo.read("a.txt"), "main\n");
+    }
+
+    #[test]
+    fn rebase_abort_restores_the_branch() {
+        let (repo, _) = rebase_repo("rebase-abort", &[("2.txt", "2\n"), ("a.txt", "two\n")]);
+        let tip = repo.head().id();
+        let outcome = repo.git.rebase("main").unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicted(_)));
+        repo.git.rebase_abort().unwrap();
+        assert!(repo.git.rebase_status().unwrap().is_none());
+        assert_eq!(repo.git.get_current_branch().unwrap(), "feature");
+        assert_eq!(repo.head().id(), tip);
+        assert_eq!(repo.read("a.txt"), "two\n");
+        assert!(repo.git.list_conflicts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn last_commits_follow_the_history_of_each_commit() {
+        let repo = TestRepo::new("last-commits");
+        let first = repo.commit(&[("a.txt", "one\n")], "first");
+        repo.commit(&[("a.txt", "two\n")], "second");
+        let restored = repo.commit(&[("a.txt", "one\n")], "restore");
+        let last_commit = |sha: Oid| {
+            let mut files = repo
+                .git
+                .get_file_list_for_commit(&sha.to_string(), None)
+                .unwrap();
+            repo.git
+                .annotate_last_commits(&sha.to_string(), None, &mut files)
+                .unwrap();
+            files[0].last_commit.as_ref().unwrap().id.clone()
+        };
+        assert_eq!(last_commit(first), first.to_string());
+        assert_eq!(last_commit(restored), restored.to_string());
+    }
+
+    #[test]
+    fn revision_matches_finds_every_ref_a_name_could_mean() {
+        let repo = TestRepo::new("revision-matches-refs");
+        let id = repo.commit(&[], "first");
+        let commit = repo.git.inner().find_commit(id).unwrap();
+        repo.git.inner().branch("release", &commit, false).unwrap();
+        repo.git
+            .inner()
+            .branch("only-branch", &commit, false)
+            .unwrap();
+        repo.git
+            .inner()
+            .tag_lightweight("release", commit.as_object(), false)
+            .unwrap();
+        assert_eq!(
+            repo.git.revision_matches("release"),
+            vec!["refs/tags/release", "refs/heads/release"]
+        );
+        assert_eq!(
+            repo.git.revision_matches("only-branch"),
+            vec!["refs/heads/only-branch"]
+        );
+        assert!(repo.git.revision_matches("missing").is_empty());
+        let err = repo.git.commit_at("release").err().unwrap();
+        assert_eq!(err.code(), ErrorCode::Ambiguous);
+        assert_eq!(repo.git.commit_at("only-branch").unwrap().id(), id);
+    }
+
+    #[test]
+    fn revision_matches_checks_sha_prefixes() {
+        let repo = TestRepo::new("revision-matches-sha");
+        let id = repo.commit(&[], "first").to_string();
+        assert_eq!(repo.git.revision_matches(&id[..7]), vec![id.clone()]);
+        assert!(repo.git.revision_matches(&id[..3]).is_empty());
+        repo.git
+            .inner()
+            .branch(&id[..7], &repo.git.commit_at("HEAD").unwrap(), false)
+            .unwrap();
+        assert_eq!(repo.git.revision_matches(&id[..7]).len(), 2);
+        assert_eq!(
+            repo.git.commit_at(&id[..7]).err().unwrap().code(),
+            ErrorCode::Ambiguous
+        );
+    }
+
+    #[test]
+    fn revision_matches_skips_revspecs() {
+        let repo = TestRepo::new("revision-matches-revspec");
+        let first = repo.commit(&[], "first");
+        repo.commit(&[], "second");
+        assert!(repo.git.revision_matches("HEAD~1").is_empty());
+        assert!(repo.git.revision_matches("HEAD^{commit}").is_empty());
+        assert!(repo.git.revision_matches("").is_empty());
+        assert_eq!(repo.git.commit_at("HEAD~1").unwrap().id(), first);
+    }
+
+    #[test]
+    fn decode_blob_prefers_byte_order_marks() {
+        let repo = TestRepo::new("decode-bom");
+        let (content, encoding) = repo
+            .git
+            .decode_blob("a.txt", b"\xff\xfeh\x00i\x00")
+            .unwrap();
+        assert_eq!((content.as_str(), encoding), ("hi", "UTF-16LE"));
+    }
+
+    #[test]
+    fn decode_blob_reads_utf8_and_detects_binary() {
+        let repo = TestRepo::new("decode-utf8");
+        assert_eq!(
+            repo.git.decode_blob("a.txt", "caf\u{e9}".as_bytes()),
+            Some(("caf\u{e9}".to_string(), "UTF-8"))
+        );
+        assert_eq!(repo.git.decode_blob("a.bin", b"a\x00b"), None);
+    }
+
+    #[test]
+    fn decode_blob_uses_the_working_tree_encoding_attribute() {
+        let repo = TestRepo::new("decode-attribute");
+        std::fs::write(
+            repo.path.join(".gitattributes"),
+            "*.txt working-tree-encoding=ISO-8859-7\n",
+        )
+        .unwrap();
+        let (content, encoding) = repo.git.decode_blob("a.txt", b"\xe1\xe2\xe3").unwrap();
+        assert_eq!(
+            (content.as_str(), encoding),
+            ("\u{3b1}\u{3b2}\u{3b3}", "ISO-8859-7")
+        );
+    }
+
+    #[test]
+    fn decode_blob_guesses_legacy_encodings() {
+        let repo = TestRepo::new("decode-guess");
+        let (content, _) = repo
+            .git
+            .decode_blob("a.txt", b"Le caf\xe9 est tr\xe8s bon, d\xe9j\xe0 servi.")
+            .unwrap();
+        assert_eq!(
+            content,
+            "Le caf\u{e9} est tr\u{e8}s bon, d\u{e9}j\u{e0} servi."
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::OnceLock;
+
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{FontStyle, Style, Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+/// Files larger than this are shown without highlighting.
+const MAX_HIGHLIGHT_BYTES: usize = 512 * 1024;
+
+struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+}
+
+fn highlighter() -> &'static Highlighter {
+    static HIGHLIGHTER: OnceLock<Highlighter> = OnceLock::new();
+    HIGHLIGHTER.get_or_init(|| Highlighter {
+        syntaxes: SyntaxSet::load_defaults_newlines(),
+        theme: ThemeSet::load_defaults().themes["InspiredGitHub"].clone(),
+    })
+}
+
+/// A run of text sharing one style, given as inline CSS.
+pub struct Token {
+    pub text: String,
+    pub style: String,
+}
+
+/// Picks a syntax from an explicit language name (e.g. from `.gitattributes`), then the file
+/// name or extension, then the first line for shebangs and modelines.
+pub fn find_syntax(
+    path: &str,
+    content: &str,
+    language: Option<&str>,
+) -> Option<&'static SyntaxReference> {
+    let syntaxes = &highlighter().syntaxes;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext);
+    language
+        .and_then(|language| {
+            syntaxes
+                .find_syntax_by_name(language)
+                .or_else(|| syntaxes.find_syntax_by_token(language))
+        })
+        .or_else(|| syntaxes.find_syntax_by_extension(name))
+        .or_else(|| extension.and_then(|ext| syntaxes.find_syntax_by_extension(ext)))
+        .or_else(|| syntaxes.find_syntax_by_first_line(content.lines().next()?))
+        .filter(|syntax| syntax.name != "Plain Text")
+}
+
+fn css(style: &Style) -> String {
+    let color = style.foreground;
+    let mut css = format!("color:#{:02x}{:02x}{:02x}", color.r, color.g, color.b);
+    if style.font_style.contains(FontStyle::BOLD) {
+        css.push_str(";font-weight:bold");
+    }
+    if style.font_style.contains(FontStyle::ITALIC) {
+        css.push_str(";font-style:italic");
+    }
+    css
+}
+
+/// Highlights a whole file so that multi-line constructs are styled correctly, returning the
+/// tokens of each line without the line ending. Returns `None` if the file is too large or
+/// cannot be highlighted.
+pub fn highlight(content: &str, syntax: &SyntaxReference) -> Option<Vec<Vec<Token>>> {
+    if content.len() > MAX_HIGHLIGHT_BYTES {
+        return None;
+    }
+    let highlighter = highlighter();
+    let mut lines = HighlightLines::new(syntax, &highlighter.theme);
+    LinesWithEndings::from(content)
+        .map(|line| {
+            let ranges = lines.highlight_line(line, &highlighter.syntaxes).ok()?;
+            let mut tokens: Vec<Token> = vec![];
+            for (style, text) in ranges {
+                let text = text.trim_end_matches(['\n', '\r']);
+                let style = css(&style);
+                match tokens.last_mut() {
+                    Some(last) if last.style == style => last.text.push_str(text),
+                    _ if text.is_empty() => (),
+                    _ => tokens.push(Token {
+                        text: text.to_string(),
+                        style,
+                    }),
+                }
+            }
+            Some(tokens)
+        })
+        .collect()
+}
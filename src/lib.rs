@@ -1 +1,4 @@
 pub mod git;
+pub mod highlight;
+pub mod markdown;
+pub mod preview;
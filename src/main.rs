@@ -3,17 +3,26 @@ use std::sync::Arc;
 use std::{net::SocketAddr, sync::Mutex};
 
 use askama::Template;
-use axum::extract::{Path, Query};
-use axum::routing::patch;
+use axum::body::Bytes;
+use axum::extract::{Form, Multipart, Path, Query};
+use axum::response::Redirect;
+use axum::routing::{patch, post};
 use axum::{
     extract::State,
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::{Html, IntoResponse, Response},
     routing::get,
     Router,
 };
 use git2::{DiffLineType, ObjectType};
-use htmx_git_client::git::{Commit, CommitFile, DiffFileItem, GitWrapper};
+use htmx_git_client::git::{
+    ApplyTarget, BlobContent, Commit, CommitFile, ConflictFile, ConflictSegment, ConflictSide,
+    ContextGap, DiffFileItem, DiffLineData, DiffSettings, DiffStat, ExpandDirection, FileBlob,
+    GitWrapper, MergeMode, MergeOutcome, MergePreview, MergeStatus, PatchEmail, RebaseAction,
+    RebaseStatus, RebaseStep, ReflogEntry, ResetMode, WhitespaceMode,
+};
+use htmx_git_client::markdown::{self, Markdown};
+use htmx_git_client::preview::{NotebookCell, NotebookOutput, Preview, PreviewKind};
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -21,41 +30,87 @@ struct AppState {
     repo: GitWrapper,
 }
 
-#[derive(Template)]
-#[template(path = "view_commit_file_list.html")]
-struct CommitFileListTemplate {
-    commit_tree: Vec<CommitFile>,
-    commit_id: String,
-    path: String
+struct Breadcrumb {
+    name: String,
+    /// Empty for the root, otherwise the path with a leading slash.
+    path: String,
+}
+
+/// The ref selector and breadcrumb path shown above trees and files.
+struct TreeNavigation {
+    branches: Vec<String>,
+    tags: Vec<String>,
+    breadcrumbs: Vec<Breadcrumb>,
+}
+
+impl TreeNavigation {
+    fn new(repo: &GitWrapper, path: &str) -> anyhow::Result<TreeNavigation> {
+        let inner = repo.inner();
+        let root = inner
+            .workdir()
+            .unwrap_or(inner.path())
+            .file_name()
+            .map_or("root".to_string(), |name| {
+                name.to_string_lossy().to_string()
+            });
+        let mut breadcrumbs = vec![Breadcrumb {
+            name: root,
+            path: String::new(),
+        }];
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            let parent = &breadcrumbs[breadcrumbs.len() - 1].path;
+            breadcrumbs.push(Breadcrumb {
+                name: segment.to_string(),
+                path: format!("{}/{}", parent, segment),
+            });
+        }
+        Ok(TreeNavigation {
+            branches: repo.list_local_branches()?,
+            tags: repo.list_tags()?,
+            breadcrumbs,
+        })
+    }
+}
+
+enum Readme {
+    Markdown { path: String, markdown: Markdown },
+    Text { path: String, content: String },
 }
 
 #[derive(Template)]
-#[template(path = "index.html")]
-struct IndexTemplate<'a> {
-    readme_content: &'a str,
+#[template(path = "view_commit_file_list.html")]
+struct CommitFileListTemplate {
     commit_tree: Vec<CommitFile>,
     commit_id: String,
-    path: String
+    rev: String,
+    path: String,
+    navigation: TreeNavigation,
+    readme: Option<Readme>,
 }
-async fn index(State(state): State<Arc<Mutex<AppState>>>) -> Result<impl IntoResponse, AppError> {
-    let repo = &state
-        .lock()
-        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
-        .repo;
-    let inner_repo = repo.inner();
 
-    let head = inner_repo.head()?;
-    let commit_id = head.peel_to_commit()?.id().to_string();
-    let commit = head.peel_to_commit()?;
-    let readme_content = repo
-        .commit_file_content(&commit.id().to_string(), "README.md")
-        .unwrap_or("".to_string());
-    let commit_tree = repo.get_file_list_for_commit(&commit.id().to_string(), None)?;
-    let template = IndexTemplate {
-        readme_content: &readme_content,
+fn view_tree(repo: &GitWrapper, rev: String, path: Option<&str>) -> Result<Response, AppError> {
+    let sha = repo.resolve_commit(&rev)?;
+    // A README that cannot be read should not keep the directory from being listed.
+    let readme = repo
+        .directory_readme(&sha, path)
+        .ok()
+        .flatten()
+        .map(|(path, content)| match PreviewKind::for_path(&path) {
+            Some(PreviewKind::Markdown) => Readme::Markdown {
+                markdown: markdown::render(&content, &sha, &path),
+                path,
+            },
+            _ => Readme::Text { path, content },
+        });
+    let mut commit_tree = repo.get_file_list_for_commit(&sha, path)?;
+    repo.annotate_last_commits(&sha, path, &mut commit_tree)?;
+    let template = CommitFileListTemplate {
         commit_tree,
-        commit_id,
-        path: "".to_string()
+        commit_id: sha,
+        rev,
+        path: path.map_or(String::new(), |path| format!("{}/", path)),
+        navigation: TreeNavigation::new(repo, path.unwrap_or(""))?,
+        readme,
     };
     match template.render() {
         Ok(html) => Ok(Html(html).into_response()),
@@ -67,6 +122,26 @@ async fn index(State(state): State<Arc<Mutex<AppState>>>) -> Result<impl IntoRes
     }
 }
 
+async fn index(State(state): State<Arc<Mutex<AppState>>>) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let rev = repo.get_current_branch()?;
+    view_tree(repo, rev, None)
+}
+
+async fn view_commit_tree(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(rev): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    view_tree(repo, rev, None)
+}
+
 #[derive(Template)]
 #[template(path = "log.html")]
 struct LogTemplate {
@@ -179,30 +254,362 @@ async fn remote_branch_list(
 #[template(path = "view_commit.html")]
 struct ViewCommitTemplate {
     diffs: Vec<DiffFileItem>,
+    stats: DiffStat,
     commit: Commit,
-    whitespace_ignored: bool,
+    current_branch: String,
+    split_view: bool,
+    settings: DiffSettings,
+    settings_query: String,
 }
 
 async fn view_commit(
     State(state): State<Arc<Mutex<AppState>>>,
     Path(sha): Path<String>,
     Query(params): Query<HashMap<String, String>>,
-) -> Result<impl IntoResponse, AppError> {
-    let ignore_whitespace = params
-        .get("ignore_whitespace")
-        .unwrap_or(&"false".to_string())
-        .parse::<bool>()
-        .unwrap_or(false);
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    // `/commit/:sha.patch` and `/commit/:sha.diff` share the route with the commit page.
+    if let Some(sha) = sha.strip_suffix(".patch") {
+        return commit_download(state, sha, "patch").await;
+    }
+    if let Some(sha) = sha.strip_suffix(".diff") {
+        return commit_download(state, sha, "diff").await;
+    }
+    let settings = diff_settings(&params)?;
+    let split_view = params
+        .get("view")
+        .map(|v| v.as_str())
+        .or(cookie_value(&headers, "diff_view"))
+        == Some("split");
     let repo = &state
         .lock()
         .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
         .repo;
     let commit = repo.find_commit(&sha)?;
-    let diffs = repo.commit_diff(&sha, ignore_whitespace)?;
+    let diffs = repo.commit_diff(&sha, &settings)?;
+    let current_branch = repo.get_current_branch()?;
     let template = ViewCommitTemplate {
+        stats: DiffStat::new(&diffs),
         diffs,
         commit,
-        whitespace_ignored: ignore_whitespace,
+        current_branch,
+        split_view,
+        settings_query: diff_settings_query(&settings),
+        settings,
+    };
+    let cookie = format!(
+        "diff_view={}; Path=/; Max-Age=31536000",
+        if split_view { "split" } else { "unified" }
+    );
+    Ok(([(header::SET_COOKIE, cookie)], HtmlTemplate(template)).into_response())
+}
+
+async fn commit_download(
+    state: Arc<Mutex<AppState>>,
+    sha: &str,
+    format: &str,
+) -> Result<Response, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let (content_type, content) = match format {
+        "patch" => ("text/x-patch; charset=utf-8", repo.commit_patch(sha)?),
+        _ => ("text/x-diff; charset=utf-8", repo.commit_raw_diff(sha)?),
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], content).into_response())
+}
+
+async fn compare_download(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(range): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let range = range
+        .strip_suffix(".mbox")
+        .ok_or(anyhow::anyhow!("Only .mbox downloads are supported"))?;
+    let (base, head) = range
+        .split_once("...")
+        .ok_or(anyhow::anyhow!("Expected a range like main...feature"))?;
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let content = repo.compare_mbox(base, head)?;
+    let name = format!("{}...{}", base, head).replace(['/', '"'], "-");
+    let disposition = format!("inline; filename=\"{}.mbox\"", name);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/mbox".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        content,
+    ))
+}
+
+fn diff_settings(params: &HashMap<String, String>) -> anyhow::Result<DiffSettings> {
+    let defaults = DiffSettings::default();
+    let flag = |name: &str, default: bool| {
+        params
+            .get(name)
+            .and_then(|v| v.parse::<bool>().ok())
+            .unwrap_or(default)
+    };
+    let whitespace = match params.get("whitespace") {
+        Some(mode) => mode.parse()?,
+        // Older links only know about ignoring all whitespace.
+        None if flag("ignore_whitespace", false) => WhitespaceMode::IgnoreAll,
+        None => defaults.whitespace,
+    };
+    Ok(DiffSettings {
+        whitespace,
+        algorithm: match params.get("algorithm") {
+            Some(algorithm) => algorithm.parse()?,
+            None => defaults.algorithm,
+        },
+        context_lines: params
+            .get("context")
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(defaults.context_lines),
+        interhunk_lines: params
+            .get("interhunk")
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(defaults.interhunk_lines),
+        find_renames: flag("renames", defaults.find_renames),
+        find_copies: flag("copies", defaults.find_copies),
+        similarity_threshold: params
+            .get("similarity")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(defaults.similarity_threshold),
+        max_files: params
+            .get("max_files")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(defaults.max_files),
+        max_file_lines: params
+            .get("max_lines")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(defaults.max_file_lines),
+        max_bytes: params
+            .get("max_bytes")
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(defaults.max_bytes),
+    })
+}
+
+fn diff_settings_query(settings: &DiffSettings) -> String {
+    format!(
+        "whitespace={}&algorithm={}&context={}&interhunk={}&renames={}&copies={}&similarity={}\
+         &max_files={}&max_lines={}&max_bytes={}",
+        settings.whitespace,
+        settings.algorithm,
+        settings.context_lines,
+        settings.interhunk_lines,
+        settings.find_renames,
+        settings.find_copies,
+        settings.similarity_threshold,
+        settings.max_files,
+        settings.max_file_lines,
+        settings.max_bytes
+    )
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[derive(Template)]
+#[template(path = "diff_file_partial.html")]
+struct DiffFileTemplate {
+    file: DiffFileItem,
+    commit: Commit,
+    split_view: bool,
+    settings_query: String,
+}
+
+async fn diff_file(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path((sha, path)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let settings = diff_settings(&params)?;
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let template = DiffFileTemplate {
+        file: repo.commit_diff_file(&sha, &path, &settings)?,
+        commit: repo.find_commit(&sha)?,
+        split_view: cookie_value(&headers, "diff_view") == Some("split"),
+        settings_query: diff_settings_query(&settings),
+    };
+    Ok(HtmlTemplate(template))
+}
+
+async fn raw(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path((rev, path)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    // Blob ids make strong ETags, and both they and the size are known without reading the
+    // content, so conditional and unsatisfiable requests are answered straight away.
+    let (id, length) = repo.raw_file_header(&rev, &path)?;
+    let etag = format!("\"{}\"", id);
+    let header_value = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    if header_value(header::IF_NONE_MATCH).is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*")
+    }) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    let disposition = format!(
+        "{}; filename*=UTF-8''{}",
+        match params.contains_key("download") {
+            true => "attachment",
+            false => "inline",
+        },
+        percent_encode(name)
+    );
+    // A range only applies if the client's copy, named by If-Range, is still current.
+    let range = match header_value(header::IF_RANGE) {
+        Some(tag) if tag != etag => None,
+        _ => header_value(header::RANGE).and_then(|range| parse_range(range, length)),
+    };
+    if range == Some(None) {
+        return Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", length))],
+        )
+            .into_response());
+    }
+    let file = repo.raw_file(&id, &path)?;
+    let content = Bytes::from(file.content);
+    let headers = [
+        (header::CONTENT_TYPE, file.mime_type),
+        (header::CONTENT_DISPOSITION, disposition),
+        (header::ETAG, etag),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        // Files are untrusted, so keep HTML and SVG from running scripts.
+        (
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; style-src 'unsafe-inline'; sandbox".to_string(),
+        ),
+    ];
+    Ok(match range {
+        Some(Some((start, end))) => (
+            StatusCode::PARTIAL_CONTENT,
+            headers,
+            [(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, length),
+            )],
+            content.slice(start..=end),
+        )
+            .into_response(),
+        _ => (headers, content).into_response(),
+    })
+}
+
+/// Parses a single `bytes=` range into inclusive offsets. Returns `None` for headers that
+/// should be ignored, such as multiple ranges, and `Some(None)` for unsatisfiable ones.
+fn parse_range(range: &str, length: usize) -> Option<Option<(usize, usize)>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            (length.saturating_sub(suffix), length.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, length.saturating_sub(1)),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<usize>().ok()?.min(length.saturating_sub(1)),
+        ),
+    };
+    match start <= end && start < length {
+        true => Some(Some((start, end))),
+        false => Some(None),
+    }
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Template)]
+#[template(path = "diff_context_partial.html")]
+struct DiffContextTemplate {
+    commit: Commit,
+    path: String,
+    lines: Vec<DiffLineData>,
+    remaining: Option<ContextGap>,
+    direction: ExpandDirection,
+    split_view: bool,
+}
+
+async fn diff_context(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path((sha, path)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let gap = ContextGap {
+        start: params
+            .get("start")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1)
+            .max(1),
+        end: params.get("end").and_then(|e| e.parse().ok()),
+        old_offset: params
+            .get("old_offset")
+            .and_then(|o| o.parse().ok())
+            .unwrap_or(0),
+    };
+    let direction = params
+        .get("direction")
+        .map(|d| d.parse::<ExpandDirection>())
+        .unwrap_or(Ok(ExpandDirection::All))?;
+    let step = params
+        .get("step")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(20)
+        .max(1);
+    let (lines, remaining) = repo.context_lines(&sha, &path, &gap, direction, step)?;
+    let template = DiffContextTemplate {
+        commit: repo.find_commit(&sha)?,
+        path,
+        lines,
+        remaining,
+        direction,
+        split_view: cookie_value(&headers, "diff_view") == Some("split"),
     };
     Ok(HtmlTemplate(template))
 }
@@ -210,42 +617,60 @@ async fn view_commit(
 #[derive(Template)]
 #[template(path = "view_commit_file.html")]
 struct ViewCommitFileTemplate {
-    content: String,
+    blob: FileBlob,
+    navigation: TreeNavigation,
+    rev: String,
+    commit_id: String,
+    path: String,
+    previewable: bool,
+    preview: Option<Preview>,
+    preview_error: Option<String>,
 }
 
 async fn view_commit_file(
     State(state): State<Arc<Mutex<AppState>>>,
-    Path((sha, path)): Path<(String, String)>,
+    Path((rev, path)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> Result<impl IntoResponse, AppError> {
     let repo = &state
         .lock()
         .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
         .repo;
+    let sha = repo.resolve_commit(&rev)?;
     let commit = repo.inner().find_commit(git2::Oid::from_str(&sha)?)?;
     let tree = commit.tree()?;
-    let entry = tree.get_path(&std::path::Path::new(&path))?;
+    let entry = tree.get_path(std::path::Path::new(&path))?;
     match entry.kind() {
-        Some(ObjectType::Tree) => {
-            let commit_tree = repo.get_file_list_for_commit(&sha, Some(&path))?;
-            match (CommitFileListTemplate {
-                commit_id: sha,
-                commit_tree,
-                path: format!("{}/", path)
-            })
-            .render()
-            {
-                Ok(html) => Ok(Html(html).into_response()),
-                Err(err) => Ok((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to render template. Error: {err}"),
-                )
-                    .into_response()),
-            }
-        }
+        Some(ObjectType::Tree) => view_tree(repo, rev, Some(path.trim_end_matches('/'))),
         _ => {
-            let commit_file_content = repo.commit_file_content(&sha, &path)?;
+            let kind = PreviewKind::for_path(&path);
+            let show_source = params.get("view").is_some_and(|view| view == "source");
+            let (preview, preview_error) = match kind {
+                Some(kind) if !show_source => {
+                    let content = match kind {
+                        PreviewKind::Image => Ok(String::new()),
+                        _ => repo
+                            .commit_file_content(&sha, &path)
+                            .map_err(anyhow::Error::from),
+                    };
+                    let preview =
+                        content.and_then(|content| Preview::render(kind, &sha, &path, &content));
+                    match preview {
+                        Ok(preview) => (Some(preview), None),
+                        Err(err) => (None, Some(err.to_string())),
+                    }
+                }
+                _ => (None, None),
+            };
             let template = ViewCommitFileTemplate {
-                content: commit_file_content,
+                blob: repo.file_blob(&sha, &path)?,
+                navigation: TreeNavigation::new(repo, &path)?,
+                rev,
+                commit_id: sha,
+                path,
+                previewable: kind.is_some(),
+                preview,
+                preview_error,
             };
             match template.render() {
                 Ok(html) => Ok(Html(html).into_response()),
@@ -259,6 +684,556 @@ async fn view_commit_file(
     }
 }
 
+#[derive(Template)]
+#[template(path = "merge.html")]
+struct MergeTemplate {
+    reference: String,
+    current_branch: String,
+    preview: MergePreview,
+}
+
+async fn merge_preview(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(reference): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let preview = repo.merge_preview(&reference)?;
+    let current_branch = repo.get_current_branch()?;
+    let template = MergeTemplate {
+        reference,
+        current_branch,
+        preview,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+async fn merge(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(reference): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let mode = params
+        .get("mode")
+        .map(|m| m.parse::<MergeMode>())
+        .unwrap_or(Ok(MergeMode::FastForward))?;
+    let redirect = match repo.merge(&reference, mode)? {
+        MergeOutcome::UpToDate => format!("/log/{}", repo.get_current_branch()?),
+        MergeOutcome::FastForwarded(sha) | MergeOutcome::Committed(sha) => {
+            format!("/commit/{}", sha)
+        }
+        MergeOutcome::Conflicted(_) => "/merge".to_string(),
+    };
+    Ok(Redirect::to(&redirect))
+}
+
+#[derive(Template)]
+#[template(path = "merge_status.html")]
+struct MergeStatusTemplate {
+    status: MergeStatus,
+}
+
+async fn merge_status(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let status = repo.merge_status()?;
+    Ok(HtmlTemplate(MergeStatusTemplate { status }))
+}
+
+async fn merge_continue(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let message = match params.get("message") {
+        Some(message) if !message.trim().is_empty() => message.to_string(),
+        _ => format!("Merge into {}", repo.get_current_branch()?),
+    };
+    let sha = repo.commit_merge(&message)?;
+    Ok(Redirect::to(&format!("/commit/{}", sha)))
+}
+
+async fn merge_abort(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    repo.abort_merge()?;
+    Ok(Redirect::to(&format!(
+        "/log/{}",
+        repo.get_current_branch()?
+    )))
+}
+
+#[derive(Template)]
+#[template(path = "conflict_list.html")]
+struct ConflictListTemplate {
+    conflicts: Vec<String>,
+}
+
+async fn conflict_list(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let conflicts = repo.list_conflicts()?;
+    Ok(HtmlTemplate(ConflictListTemplate { conflicts }))
+}
+
+#[derive(Template)]
+#[template(path = "conflict_file.html")]
+struct ConflictFileTemplate {
+    file: ConflictFile,
+}
+
+async fn conflict_file(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(path): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let file = repo.conflict_file(&path)?;
+    Ok(HtmlTemplate(ConflictFileTemplate { file }))
+}
+
+async fn resolve_conflict(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(path): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    match params.get("action").map(|a| a.as_str()) {
+        Some("ours") => repo.take_conflict_side(&path, ConflictSide::Ours)?,
+        Some("theirs") => repo.take_conflict_side(&path, ConflictSide::Theirs)?,
+        Some("delete") => repo.resolve_conflict(&path, None)?,
+        Some("edit") => {
+            let content = params.get("content").map(|c| c.replace("\r\n", "\n"));
+            repo.resolve_conflict(&path, Some(content.unwrap_or_default().as_bytes()))?
+        }
+        Some("hunks") => {
+            let file = repo.conflict_file(&path)?;
+            let sides = (0..file.conflict_count())
+                .map(|i| match params.get(&format!("hunk_{}", i)) {
+                    Some(side) => side.parse::<ConflictSide>(),
+                    None => Ok(ConflictSide::Ours),
+                })
+                .collect::<anyhow::Result<Vec<ConflictSide>>>()?;
+            repo.resolve_conflict(&path, Some(file.resolve(&sides)?.as_bytes()))?
+        }
+        _ => return Err(anyhow::anyhow!("Unknown conflict resolution").into()),
+    };
+    Ok(Redirect::to("/conflicts"))
+}
+
+async fn cherry_pick_commit(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(sha): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let sha = repo.resolve_commit(&sha)?;
+    let mainline = params.get("mainline").and_then(|m| m.parse::<u32>().ok());
+    let record_origin = params.contains_key("record_origin");
+    let outcome = repo.cherry_pick(&sha, mainline, record_origin)?;
+    Ok(Redirect::to(&pick_redirect(outcome)))
+}
+
+async fn revert_commit(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(sha): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let sha = repo.resolve_commit(&sha)?;
+    let mainline = params.get("mainline").and_then(|m| m.parse::<u32>().ok());
+    let outcome = repo.revert(&sha, mainline)?;
+    Ok(Redirect::to(&pick_redirect(outcome)))
+}
+
+fn pick_redirect(outcome: MergeOutcome) -> String {
+    match outcome {
+        MergeOutcome::FastForwarded(sha) | MergeOutcome::Committed(sha) => {
+            format!("/commit/{}", sha)
+        }
+        MergeOutcome::UpToDate | MergeOutcome::Conflicted(_) => "/merge".to_string(),
+    }
+}
+
+#[derive(Template)]
+#[template(path = "reset.html")]
+struct ResetTemplate {
+    commit: Commit,
+    current_branch: String,
+    unreachable: Vec<Commit>,
+}
+
+async fn reset_preview(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(sha): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let template = ResetTemplate {
+        commit: repo.find_commit(&sha)?,
+        current_branch: repo.get_current_branch()?,
+        unreachable: repo.unreachable_after_reset(&sha)?,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+#[derive(Template)]
+#[template(path = "reset_done.html")]
+struct ResetDoneTemplate {
+    sha: String,
+    current_branch: String,
+    mode: ResetMode,
+}
+
+async fn reset(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(sha): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let mode = params
+        .get("mode")
+        .map(|m| m.parse::<ResetMode>())
+        .unwrap_or(Ok(ResetMode::Mixed))?;
+    repo.reset(&sha, mode)?;
+    let template = ResetDoneTemplate {
+        sha,
+        current_branch: repo.get_current_branch()?,
+        mode,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+async fn undo_reset(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let mode = params
+        .get("mode")
+        .map(|m| m.parse::<ResetMode>())
+        .unwrap_or(Ok(ResetMode::Mixed))?;
+    repo.undo_reset(mode)?;
+    Ok(Redirect::to(&format!(
+        "/log/{}",
+        repo.get_current_branch()?
+    )))
+}
+
+#[derive(Template)]
+#[template(path = "reflog.html")]
+struct ReflogTemplate {
+    reference: String,
+    branches: Vec<String>,
+    entries: Vec<ReflogEntry>,
+}
+
+async fn reflog(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(reference): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let template = ReflogTemplate {
+        entries: repo.reflog(&reference)?,
+        branches: repo.list_local_branches()?,
+        reference,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+async fn create_branch(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let name = params
+        .get("name")
+        .ok_or(anyhow::anyhow!("Missing branch name"))?;
+    let sha = params
+        .get("sha")
+        .ok_or(anyhow::anyhow!("Missing commit sha"))?;
+    repo.create_branch(name, sha)?;
+    Ok(Redirect::to(&format!("/log/refs/heads/{}", name)))
+}
+
+#[derive(Template)]
+#[template(path = "rebase.html")]
+struct RebaseTemplate {
+    upstream: String,
+    current_branch: String,
+    commits: Vec<Commit>,
+}
+
+async fn rebase_planner(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(upstream): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    // The commits the rebase replays, oldest first. Git skips merge commits.
+    let mut commits = repo
+        .list_commits(&format!("{}..HEAD", upstream), None)?
+        .filter(|commit| commit.parents.len() <= 1)
+        .collect::<Vec<Commit>>();
+    commits.reverse();
+    let template = RebaseTemplate {
+        commits,
+        current_branch: repo.get_current_branch()?,
+        upstream,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+async fn rebase(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(upstream): Path<String>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let outcome = match params.get("mode").map(|m| m.as_str()) {
+        Some("plan") => {
+            let mut steps = (0..)
+                .map_while(|i| {
+                    let id = params.get(&format!("id_{}", i))?;
+                    Some((i, id))
+                })
+                .map(|(i, id)| {
+                    let order = params
+                        .get(&format!("order_{}", i))
+                        .and_then(|o| o.parse::<i64>().ok())
+                        .unwrap_or(i);
+                    let action = params
+                        .get(&format!("action_{}", i))
+                        .map(|a| a.parse::<RebaseAction>())
+                        .unwrap_or(Ok(RebaseAction::Pick))?;
+                    let message = match action {
+                        RebaseAction::Reword => params
+                            .get(&format!("message_{}", i))
+                            .map(|m| m.replace("\r\n", "\n")),
+                        _ => None,
+                    };
+                    Ok((
+                        order,
+                        RebaseStep {
+                            action,
+                            id: id.to_string(),
+                            message,
+                        },
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<(i64, RebaseStep)>>>()?;
+            steps.sort_by_key(|(order, _)| *order);
+            repo.rebase_interactive(&upstream, steps.into_iter().map(|(_, step)| step).collect())?
+        }
+        _ => repo.rebase(&upstream)?,
+    };
+    Ok(Redirect::to(&rebase_redirect(repo, outcome)?))
+}
+
+fn rebase_redirect(repo: &GitWrapper, outcome: MergeOutcome) -> anyhow::Result<String> {
+    Ok(match outcome {
+        MergeOutcome::Conflicted(_) => "/rebase".to_string(),
+        _ => format!("/log/{}", repo.get_current_branch()?),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "rebase_status.html")]
+struct RebaseStatusTemplate {
+    status: Option<RebaseStatus>,
+}
+
+async fn rebase_status(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let status = repo.rebase_status()?;
+    Ok(HtmlTemplate(RebaseStatusTemplate { status }))
+}
+
+async fn rebase_continue(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let outcome = repo.rebase_continue()?;
+    Ok(Redirect::to(&rebase_redirect(repo, outcome)?))
+}
+
+async fn rebase_skip(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let outcome = repo.rebase_skip()?;
+    Ok(Redirect::to(&rebase_redirect(repo, outcome)?))
+}
+
+async fn rebase_abort(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    repo.rebase_abort()?;
+    Ok(Redirect::to(&format!(
+        "/log/{}",
+        repo.get_current_branch()?
+    )))
+}
+
+#[derive(Template)]
+#[template(path = "apply.html")]
+struct ApplyTemplate {
+    current_branch: String,
+}
+
+async fn apply_upload(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let template = ApplyTemplate {
+        current_branch: repo.get_current_branch()?,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+#[derive(Template)]
+#[template(path = "apply_preview.html")]
+struct ApplyPreviewTemplate {
+    current_branch: String,
+    patches: Vec<PatchEmail>,
+}
+
+async fn apply_preview(
+    State(state): State<Arc<Mutex<AppState>>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let mut content = None;
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() == Some("patch") {
+            content = Some(field.bytes().await?);
+        }
+    }
+    let content = content.ok_or(anyhow::anyhow!("No patch was uploaded"))?;
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    repo.store_patch(&content)?;
+    let template = ApplyPreviewTemplate {
+        current_branch: repo.get_current_branch()?,
+        patches: repo.preview_patches()?,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+#[derive(Template)]
+#[template(path = "apply_result.html")]
+struct ApplyResultTemplate {
+    current_branch: String,
+    target: ApplyTarget,
+    patches: Vec<PatchEmail>,
+    /// Set when a patch failed, leaving the rest of the series uploaded.
+    stopped: bool,
+}
+
+async fn apply(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Form(params): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, AppError> {
+    let target = params
+        .get("target")
+        .map(|t| t.parse::<ApplyTarget>())
+        .unwrap_or(Ok(ApplyTarget::Commit))?;
+    let skip_failed = params.contains_key("skip_failed");
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    let patches = repo.apply_patches(target, skip_failed)?;
+    let template = ApplyResultTemplate {
+        current_branch: repo.get_current_branch()?,
+        target,
+        stopped: patches.iter().any(|patch| patch.error.is_some()),
+        patches,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+async fn apply_discard(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repo = &state
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Could not get reference to repo"))?
+        .repo;
+    repo.discard_patch()?;
+    Ok(Redirect::to("/apply"))
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -278,8 +1253,36 @@ async fn main() {
         .route("/log/*reference", get(log))
         .route("/remote/branches/*remote", get(remote_branch_list))
         .route("/checkout/*branch", patch(checkout_branch))
+        .route("/commit/:sha/file", get(view_commit_tree))
         .route("/commit/:sha/file/*path", get(view_commit_file))
         .route("/commit/:sha", get(view_commit))
+        .route("/commit/:sha/context/*path", get(diff_context))
+        .route("/commit/:sha/diff/*path", get(diff_file))
+        .route("/raw/:rev/*path", get(raw))
+        .route("/compare/*range", get(compare_download))
+        .route("/apply", get(apply_upload).post(apply))
+        .route("/apply/preview", post(apply_preview))
+        .route("/apply/discard", post(apply_discard))
+        .route("/commit/:sha/cherry-pick", post(cherry_pick_commit))
+        .route("/commit/:sha/revert", post(revert_commit))
+        .route("/reflog/*reference", get(reflog))
+        .route("/branch", post(create_branch))
+        .route("/reset/undo", post(undo_reset))
+        .route("/reset/:sha", get(reset_preview).post(reset))
+        .route("/rebase", get(rebase_status))
+        .route("/rebase/continue", post(rebase_continue))
+        .route("/rebase/skip", post(rebase_skip))
+        .route("/rebase/abort", post(rebase_abort))
+        .route("/rebase/onto/*upstream", get(rebase_planner).post(rebase))
+        .route("/merge", get(merge_status))
+        .route("/conflicts", get(conflict_list))
+        .route(
+            "/conflicts/file/*path",
+            get(conflict_file).post(resolve_conflict),
+        )
+        .route("/merge/continue", post(merge_continue))
+        .route("/merge/abort", post(merge_abort))
+        .route("/merge/ref/*reference", get(merge_preview).post(merge))
         .with_state(shared_state)
         .nest_service(
             "/assets",
@@ -317,11 +1320,24 @@ struct AppError(anyhow::Error);
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        match self.0.downcast_ref::<git2::Error>() {
+            Some(err) if err.code() == git2::ErrorCode::NotFound => {
+                (StatusCode::NOT_FOUND, err.message().to_string()).into_response()
+            }
+            Some(err)
+                if matches!(
+                    err.code(),
+                    git2::ErrorCode::Ambiguous | git2::ErrorCode::Invalid
+                ) =>
+            {
+                (StatusCode::BAD_REQUEST, err.message().to_string()).into_response()
+            }
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {}", self.0),
+            )
+                .into_response(),
+        }
     }
 }
 
@@ -335,3 +1351,38 @@ where
         Self(err.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_range_reads_bounded_ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100), Some(Some((0, 9))));
+        assert_eq!(parse_range(" bytes=10-10 ", 100), Some(Some((10, 10))));
+        assert_eq!(parse_range("bytes=90-200", 100), Some(Some((90, 99))));
+    }
+
+    #[test]
+    fn parse_range_reads_open_and_suffix_ranges() {
+        assert_eq!(parse_range("bytes=90-", 100), Some(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-10", 100), Some(Some((90, 99))));
+        assert_eq!(parse_range("bytes=-500", 100), Some(Some((0, 99))));
+    }
+
+    #[test]
+    fn parse_range_rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=100-", 100), Some(None));
+        assert_eq!(parse_range("bytes=20-10", 100), Some(None));
+        assert_eq!(parse_range("bytes=-10", 0), None);
+        assert_eq!(parse_range("bytes=0-0", 0), Some(None));
+    }
+
+    #[test]
+    fn parse_range_ignores_unsupported_headers() {
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+        assert_eq!(parse_range("bytes=a-b", 100), None);
+        assert_eq!(parse_range("bytes=5", 100), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use comrak::nodes::{AstNode, NodeValue};
+use comrak::{Anchorizer, Arena, ComrakOptions};
+
+/// Prefixed to every id in rendered Markdown so that documents cannot clobber the ids the page
+/// itself relies on. Fragment links are rewritten to match.
+const ID_PREFIX: &str = "user-content-";
+
+/// A Markdown document rendered to sanitized HTML.
+pub struct Markdown {
+    pub html: String,
+    pub toc: Vec<Heading>,
+}
+
+pub struct Heading {
+    pub level: u8,
+    pub id: String,
+    pub text: String,
+}
+
+fn options() -> ComrakOptions {
+    let mut options = ComrakOptions::default();
+    options.extension.strikethrough = true;
+    options.extension.table = true;
+    options.extension.autolink = true;
+    options.extension.tasklist = true;
+    options.extension.footnotes = true;
+    options.extension.header_ids = Some(ID_PREFIX.to_string());
+    // Raw HTML is passed through here and sanitized afterwards.
+    options.render.unsafe_ = true;
+    options
+}
+
+fn sanitizer() -> &'static ammonia::Builder<'static> {
+    static SANITIZER: OnceLock<ammonia::Builder<'static>> = OnceLock::new();
+    SANITIZER.get_or_init(|| {
+        let mut builder = ammonia::Builder::default();
+        builder
+            .add_tags(["input"])
+            .add_tag_attributes("input", ["checked"])
+            .set_tag_attribute_value("input", "type", "checkbox")
+            .set_tag_attribute_value("input", "disabled", "")
+            .add_tag_attributes("a", ["id", "aria-hidden"])
+            .add_generic_attributes(["id"])
+            .add_allowed_classes("a", ["anchor"])
+            .attribute_filter(|_, attribute, value| match attribute {
+                "id" if !value.starts_with(ID_PREFIX) => {
+                    Some(Cow::Owned(format!("{}{}", ID_PREFIX, value)))
+                }
+                "href" => match value.strip_prefix('#') {
+                    Some(fragment) if !fragment.starts_with(ID_PREFIX) => {
+                        Some(Cow::Owned(format!("#{}{}", ID_PREFIX, fragment)))
+                    }
+                    _ => Some(Cow::Borrowed(value)),
+                },
+                _ => Some(Cow::Borrowed(value)),
+            });
+        builder
+    })
+}
+
+fn collect_text<'a>(node: &'a AstNode<'a>, output: &mut String) {
+    match node.data.borrow().value {
+        NodeValue::Text(ref literal)
+        | NodeValue::Code(comrak::nodes::NodeCode { ref literal, .. }) => output.push_str(literal),
+        NodeValue::LineBreak | NodeValue::SoftBreak => output.push(' '),
+        _ => {
+            for child in node.children() {
+                collect_text(child, output);
+            }
+        }
+    }
+}
+
+/// Resolves a link relative to the directory of the document, returning the repository path
+/// and any query or fragment. Links with a scheme or to a fragment of the same document are
+/// left alone.
+fn resolve(directory: &str, url: &str) -> Option<(String, String)> {
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    let (path, suffix) = url.split_at(end);
+    if path.is_empty() || path.starts_with("//") || path.split('/').next()?.contains(':') {
+        return None;
+    }
+    let mut segments: Vec<&str> = match path.starts_with('/') {
+        true => vec![],
+        false => directory.split('/').filter(|s| !s.is_empty()).collect(),
+    };
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some((segments.join("/"), suffix.to_string()))
+}
+
+/// Renders a Markdown file found at `path` in commit `commit`. Relative links point at the
+/// file view and relative images at the raw file, both at the same commit.
+pub fn render(content: &str, commit: &str, path: &str) -> Markdown {
+    let directory = path.rsplit_once('/').map_or("", |(directory, _)| directory);
+    let arena = Arena::new();
+    let options = options();
+    let root = comrak::parse_document(&arena, content, &options);
+    let mut anchorizer = Anchorizer::new();
+    let mut toc = vec![];
+    for node in root.descendants() {
+        let mut data = node.data.borrow_mut();
+        match data.value {
+            NodeValue::Link(ref mut link) => {
+                if let Some((path, suffix)) = resolve(directory, &link.url) {
+                    link.url = match path.is_empty() {
+                        true => format!("/commit/{}/file{}", commit, suffix),
+                        false => format!("/commit/{}/file/{}{}", commit, path, suffix),
+                    };
+                }
+            }
+            NodeValue::Image(ref mut image) => {
+                if let Some((path, _)) = resolve(directory, &image.url) {
+                    image.url = format!("/raw/{}/{}", commit, path);
+                }
+            }
+            NodeValue::Heading(ref heading) => {
+                let level = heading.level;
+                drop(data);
+                let mut text = String::new();
+                collect_text(node, &mut text);
+                // The same anchorizer the renderer uses, so the ids line up.
+                let id = anchorizer.anchorize(text.clone());
+                toc.push(Heading {
+                    level,
+                    id: format!("{}{}", ID_PREFIX, id),
+                    text,
+                });
+            }
+            _ => (),
+        }
+    }
+    let mut html = vec![];
+    if comrak::format_html(root, &options, &mut html).is_err() {
+        return Markdown {
+            html: String::new(),
+            toc,
+        };
+    }
+    Markdown {
+        html: sanitizer()
+            .clean(&String::from_utf8_lossy(&html))
+            .to_string(),
+        toc,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_relative_links_against_the_directory() {
+        assert_eq!(
+            resolve("docs", "guide.md"),
+            Some(("docs/guide.md".to_string(), String::new()))
+        );
+        assert_eq!(
+            resolve("docs/api", "../guide.md#setup"),
+            Some(("docs/guide.md".to_string(), "#setup".to_string()))
+        );
+        assert_eq!(
+            resolve("", "./src/main.rs?plain=1"),
+            Some(("src/main.rs".to_string(), "?plain=1".to_string()))
+        );
+    }
+
+    #[test]
+    fn resolve_root_relative_links() {
+        assert_eq!(
+            resolve("docs", "/README.md"),
+            Some(("README.md".to_string(), String::new()))
+        );
+        assert_eq!(resolve("docs", "/"), Some((String::new(), String::new())));
+    }
+
+    #[test]
+    fn resolve_stops_at_the_repository_root() {
+        assert_eq!(
+            resolve("docs", "../../../etc/passwd"),
+            Some(("etc/passwd".to_string(), String::new()))
+        );
+    }
+
+    #[test]
+    fn resolve_leaves_absolute_and_fragment_links_alone() {
+        assert_eq!(resolve("docs", "https://example.com/a.md"), None);
+        assert_eq!(resolve("docs", "mailto:someone@example.com"), None);
+        assert_eq!(resolve("docs", "//example.com/a.md"), None);
+        assert_eq!(resolve("docs", "#usage"), None);
+    }
+
+    #[test]
+    fn render_links_the_repository_root_to_the_file_view() {
+        let markdown = render("[root](/)", "abc", "docs/README.md");
+        assert!(markdown.html.contains("href=\"/commit/abc/file\""));
+    }
+}
@@ -0,0 +1,315 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::highlight::{self, Token};
+use crate::markdown::{self, Markdown};
+
+/// Tables with more rows than this are cut off.
+const MAX_TABLE_ROWS: usize = 5000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PreviewKind {
+    Markdown,
+    Image,
+    Table,
+    Notebook,
+}
+
+impl PreviewKind {
+    pub fn for_path(path: &str) -> Option<PreviewKind> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "md" | "markdown" | "mdown" | "mkd" => Some(PreviewKind::Markdown),
+            "png" | "jpg" | "jpeg" | "gif" | "svg" | "webp" | "bmp" | "ico" => {
+                Some(PreviewKind::Image)
+            }
+            "csv" | "tsv" => Some(PreviewKind::Table),
+            "ipynb" => Some(PreviewKind::Notebook),
+            _ => None,
+        }
+    }
+}
+
+pub enum Preview {
+    Markdown(Markdown),
+    Image,
+    Table {
+        header: Vec<String>,
+        rows: Vec<Vec<String>>,
+        truncated: bool,
+    },
+    Notebook(Vec<NotebookCell>),
+}
+
+impl Preview {
+    /// Builds the preview of a file at `commit`. Images need no content as they are loaded from
+    /// the raw route.
+    pub fn render(kind: PreviewKind, commit: &str, path: &str, content: &str) -> Result<Preview> {
+        Ok(match kind {
+            PreviewKind::Markdown => Preview::Markdown(markdown::render(content, commit, path)),
+            PreviewKind::Image => Preview::Image,
+            PreviewKind::Table => {
+                let delimiter = match path.to_lowercase().ends_with(".tsv") {
+                    true => b'\t',
+                    false => b',',
+                };
+                table(content, delimiter)?
+            }
+            PreviewKind::Notebook => Preview::Notebook(notebook(content, commit, path)?),
+        })
+    }
+}
+
+fn table(content: &str, delimiter: u8) -> Result<Preview> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let header = reader.headers()?.iter().map(str::to_string).collect();
+    let mut rows = vec![];
+    for record in reader.records() {
+        if rows.len() == MAX_TABLE_ROWS {
+            return Ok(Preview::Table {
+                header,
+                rows,
+                truncated: true,
+            });
+        }
+        rows.push(record?.iter().map(str::to_string).collect());
+    }
+    Ok(Preview::Table {
+        header,
+        rows,
+        truncated: false,
+    })
+}
+
+pub enum NotebookCell {
+    Markdown(Markdown),
+    Code {
+        execution_count: Option<i64>,
+        lines: Vec<Vec<Token>>,
+        outputs: Vec<NotebookOutput>,
+    },
+    Raw(String),
+}
+
+pub enum NotebookOutput {
+    Text(String),
+    Image { mime_type: String, data: String },
+    Error(String),
+}
+
+/// Notebook sources and text outputs are either a string or a list of lines.
+fn joined(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+/// Tracebacks are colored with ANSI escape sequences, which mean nothing in HTML.
+fn strip_ansi(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' => {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn notebook_output(output: &Value) -> Option<NotebookOutput> {
+    match output["output_type"].as_str()? {
+        "stream" => Some(NotebookOutput::Text(joined(&output["text"]))),
+        "error" => {
+            let traceback = output["traceback"]
+                .as_array()
+                .map(|lines| {
+                    lines
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .map(strip_ansi)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .unwrap_or_default();
+            Some(NotebookOutput::Error(traceback))
+        }
+        _ => {
+            let data = &output["data"];
+            ["image/png", "image/jpeg", "image/gif"]
+                .iter()
+                .find_map(|mime_type| {
+                    Some(NotebookOutput::Image {
+                        mime_type: mime_type.to_string(),
+                        data: joined(data.get(*mime_type)?).replace('\n', ""),
+                    })
+                })
+                .or_else(|| Some(NotebookOutput::Text(joined(data.get("text/plain")?))))
+        }
+    }
+}
+
+fn notebook(content: &str, commit: &str, path: &str) -> Result<Vec<NotebookCell>> {
+    let notebook: Value = serde_json::from_str(content)?;
+    let language = notebook["metadata"]["language_info"]["name"]
+        .as_str()
+        .or(notebook["metadata"]["kernelspec"]["language"].as_str())
+        .unwrap_or("python");
+    let cells = notebook["cells"]
+        .as_array()
+        .ok_or(anyhow::anyhow!("The notebook has no cells"))?;
+    Ok(cells
+        .iter()
+        .map(|cell| {
+            let source = joined(&cell["source"]);
+            match cell["cell_type"].as_str() {
+                Some("markdown") => NotebookCell::Markdown(markdown::render(&source, commit, path)),
+                Some("code") => NotebookCell::Code {
+                    execution_count: cell["execution_count"].as_i64(),
+                    lines: highlight::find_syntax("", &source, Some(language))
+                        .and_then(|syntax| highlight::highlight(&source, syntax))
+                        .unwrap_or_else(|| {
+                            source
+                                .lines()
+                                .map(|line| {
+                                    vec![Token {
+                                        text: line.to_string(),
+                                        style: String::new(),
+                                    }]
+                                })
+                                .collect()
+                        }),
+                    outputs: cell["outputs"]
+                        .as_array()
+                        .map(|outputs| outputs.iter().filter_map(notebook_output).collect())
+                        .unwrap_or_default(),
+                },
+                _ => NotebookCell::Raw(source),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_rows(preview: Preview) -> (Vec<String>, Vec<Vec<String>>, bool) {
+        match preview {
+            Preview::Table {
+                header,
+                rows,
+                truncated,
+            } => (header, rows, truncated),
+            _ => panic!("not a table"),
+        }
+    }
+
+    #[test]
+    fn table_stops_at_the_row_limit() {
+        let rows = |count: usize| -> String {
+            std::iter::once("n".to_string())
+                .chain((0..count).map(|n| n.to_string()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let (_, shown, truncated) = table_rows(table(&rows(MAX_TABLE_ROWS), b',').unwrap());
+        assert_eq!((shown.len(), truncated), (MAX_TABLE_ROWS, false));
+        let (header, shown, truncated) =
+            table_rows(table(&rows(MAX_TABLE_ROWS + 1), b',').unwrap());
+        assert_eq!((shown.len(), truncated), (MAX_TABLE_ROWS, true));
+        assert_eq!(header, vec!["n"]);
+        assert_eq!(
+            shown.last().unwrap(),
+            &vec![(MAX_TABLE_ROWS - 1).to_string()]
+        );
+    }
+
+    #[test]
+    fn table_splits_tsv_files_on_tabs() {
+        let content = "a\tb,c\n1\t2,3\n";
+        let (header, rows, _) =
+            table_rows(Preview::render(PreviewKind::Table, "c", "data.TSV", content).unwrap());
+        assert_eq!(header, vec!["a", "b,c"]);
+        assert_eq!(rows, vec![vec!["1", "2,3"]]);
+        let (header, rows, _) =
+            table_rows(Preview::render(PreviewKind::Table, "c", "data.csv", content).unwrap());
+        assert_eq!(header, vec!["a\tb", "c"]);
+        assert_eq!(rows, vec![vec!["1\t2", "3"]]);
+    }
+
+    #[test]
+    fn joined_accepts_strings_and_lists_of_lines() {
+        assert_eq!(joined(&serde_json::json!("a\nb")), "a\nb");
+        assert_eq!(joined(&serde_json::json!(["a\n", "b"])), "a\nb");
+        assert_eq!(joined(&serde_json::json!(["a", 1, "b"])), "ab");
+        assert_eq!(joined(&serde_json::json!(null)), "");
+    }
+
+    #[test]
+    fn strip_ansi_removes_escape_sequences() {
+        assert_eq!(
+            strip_ansi("\u{1b}[0;31mValueError\u{1b}[0m: bad \u{1b}[1mvalue"),
+            "ValueError: bad value"
+        );
+        assert_eq!(strip_ansi("plain"), "plain");
+    }
+
+    #[test]
+    fn notebook_reads_cells_and_outputs() {
+        let content = serde_json::json!({
+            "metadata": {"language_info": {"name": "python"}},
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Title"]},
+                {
+                    "cell_type": "code",
+                    "execution_count": 3,
+                    "source": ["x = 1\n", "x"],
+                    "outputs": [
+                        {"output_type": "stream", "text": ["hello\n"]},
+                        {"output_type": "execute_result", "data": {"text/plain": "1"}},
+                        {"output_type": "display_data", "data": {"image/png": "iVBO\nRw=="}},
+                        {"output_type": "error", "traceback": ["\u{1b}[31mError\u{1b}[0m", "line"]},
+                    ],
+                },
+                {"cell_type": "raw", "source": "raw text"},
+            ],
+        })
+        .to_string();
+        let cells = notebook(&content, "c", "a.ipynb").unwrap();
+        assert_eq!(cells.len(), 3);
+        assert!(matches!(cells[0], NotebookCell::Markdown(_)));
+        let NotebookCell::Code {
+            execution_count,
+            lines,
+            outputs,
+        } = &cells[1]
+        else {
+            panic!("not a code cell");
+        };
+        assert_eq!(*execution_count, Some(3));
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(&outputs[0], NotebookOutput::Text(text) if text == "hello\n"));
+        assert!(matches!(&outputs[1], NotebookOutput::Text(text) if text == "1"));
+        assert!(matches!(
+            &outputs[2],
+            NotebookOutput::Image { mime_type, data } if mime_type == "image/png" && data == "iVBORw=="
+        ));
+        assert!(matches!(&outputs[3], NotebookOutput::Error(text) if text == "Error\nline"));
+        assert!(matches!(&cells[2], NotebookCell::Raw(text) if text == "raw text"));
+
+        assert!(notebook("{}", "c", "a.ipynb").is_err());
+        assert!(notebook("not json", "c", "a.ipynb").is_err());
+    }
+}